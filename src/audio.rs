@@ -0,0 +1,708 @@
+use crate::generator::{Adsr, Lfo};
+use crate::slew::{Slew, SlewRates};
+use cpal::traits::DeviceTrait;
+use cpal::{
+    Device, FromSample, Sample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig,
+    SupportedBufferSize, SupportedStreamConfigRange,
+};
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::time::{Duration, Instant};
+
+/// The value a CV channel is heading for and how fast it gets there.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CvTarget {
+    pub value: f32,
+    pub slew: Slew,
+}
+
+/// What an event does to its channel.
+#[derive(Debug, Clone, Copy)]
+pub enum CvAction {
+    /// Heads for a new value.
+    Set(CvTarget),
+    /// Jumps to `level` for `ms`, then back to 0.
+    Trigger { level: f32, ms: f32 },
+    /// Flips between 0 and `level`.
+    Toggle { level: f32 },
+    /// Pulses to `level` for `ms`, `hz` times a second until `hz` is 0.
+    Clock {
+        hz: f32,
+        align: ClockAlign,
+        level: f32,
+        ms: f32,
+    },
+    /// Runs an LFO at `hz` from `cycles` periods in.
+    Lfo { lfo: Lfo, hz: f32, cycles: f64 },
+    /// Runs an envelope, opening or closing its gate when `gate` is set.
+    /// Opening an open gate retriggers it, closing a closed one does nothing.
+    Envelope { adsr: Adsr, gate: Option<bool> },
+}
+
+/// How a clock event lines up the pulses, given as the fraction of a period
+/// until the next one.
+#[derive(Debug, Clone, Copy)]
+pub enum ClockAlign {
+    /// Keeps the running phase.
+    Keep,
+    /// Jumps to the phase, 0 pulses right away.
+    Restart(f32),
+    /// Moves to the phase the shortest way round, so a small correction
+    /// never repeats or skips a pulse. A stopped clock restarts.
+    Follow(f32),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CvEvent {
+    pub channel: usize,
+    pub action: CvAction,
+    /// When the value was sent: the arrival time, or the bundle timetag.
+    pub time: Instant,
+}
+
+/// What drives a channel from frame to frame, on top of its target.
+#[derive(Debug, Clone, Copy, Default)]
+enum Generator {
+    #[default]
+    None,
+    Clock(PulseClock),
+    Lfo {
+        lfo: Lfo,
+        /// Periods per frame.
+        rate: f64,
+        cycles: f64,
+    },
+    Envelope(EnvelopeState),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EnvelopeState {
+    adsr: Adsr,
+    gate: bool,
+    /// Level at the last gate change, and frames since.
+    from: f32,
+    frames: u64,
+}
+
+impl EnvelopeState {
+    fn level(&self, sample_rate: f32) -> f32 {
+        self.adsr
+            .level(self.gate, self.from, self.frames as f32 / sample_rate)
+    }
+}
+
+/// A channel's running clock, advanced once per frame.
+#[derive(Debug, Clone, Copy, Default)]
+struct PulseClock {
+    /// Periods per frame, 0 while stopped.
+    rate: f64,
+    /// Periods left until the next pulse.
+    until: f64,
+    level: f32,
+    frames: u32,
+}
+
+/// Pending CV events between the OSC thread and the audio callback.
+const EVENT_CAPACITY: usize = 1024;
+
+/// Creates the lock-free queue feeding CV events to a renderer.
+pub fn cv_events() -> (Producer<CvEvent>, Consumer<CvEvent>) {
+    RingBuffer::new(EVENT_CAPACITY)
+}
+
+/// Maps wall-clock time onto the renderer's running frame count. The clock
+/// follows the sample count rather than callback times, re-anchoring only when
+/// the two drift apart by more than a buffer, e.g. after an xrun.
+struct FrameClock {
+    sample_rate: f32,
+    anchor: Option<(Instant, u64)>,
+}
+
+impl FrameClock {
+    /// The wall-clock time corresponding to the start of the buffer at `frame`.
+    fn buffer_start(&mut self, now: Instant, frame: u64, buffer_frames: usize) -> Instant {
+        let tolerance = Duration::from_secs_f32(buffer_frames as f32 / self.sample_rate);
+        if let Some((instant, anchor_frame)) = self.anchor {
+            let elapsed = (frame - anchor_frame) as f32 / self.sample_rate;
+            let expected = instant + Duration::from_secs_f32(elapsed);
+            let drift = if now > expected {
+                now - expected
+            } else {
+                expected - now
+            };
+            if drift <= tolerance {
+                return expected;
+            }
+        }
+        self.anchor = Some((now, frame));
+        now
+    }
+
+    /// Frames from `start` until `time`, zero if `time` has already passed.
+    fn frames_until(&self, start: Instant, time: Instant) -> u64 {
+        (time.saturating_duration_since(start).as_secs_f32() * self.sample_rate) as u64
+    }
+}
+
+/// The audio side of the CV outputs. It owns all of its state and only talks
+/// to the OSC thread through a wait-free ring buffer, so rendering never
+/// blocks or allocates.
+///
+/// Each event is applied at the sample its timestamp plus `latency` falls on;
+/// events that are already late are applied at the start of the next buffer.
+pub struct Renderer {
+    events: Consumer<CvEvent>,
+    latency: Duration,
+    clock: FrameClock,
+    /// Frames rendered so far.
+    frame: u64,
+    /// Events waiting for their frame, sorted by due frame. Never grows past
+    /// its initial capacity.
+    pending: Vec<(u64, CvEvent)>,
+    targets: Vec<CvTarget>,
+    rates: Vec<SlewRates>,
+    current: Vec<f32>,
+    /// Samples left in each channel's trigger pulse.
+    pulse_left: Vec<u32>,
+    toggled: Vec<bool>,
+    /// Each channel's clock, LFO or envelope.
+    generators: Vec<Generator>,
+}
+
+impl Renderer {
+    pub fn new(
+        events: Consumer<CvEvent>,
+        cv_channels: usize,
+        sample_rate: f32,
+        latency: Duration,
+    ) -> Self {
+        Renderer {
+            events,
+            latency,
+            clock: FrameClock {
+                sample_rate,
+                anchor: None,
+            },
+            frame: 0,
+            pending: Vec::with_capacity(EVENT_CAPACITY),
+            targets: vec![CvTarget::default(); cv_channels],
+            rates: vec![Slew::default().rates(sample_rate); cv_channels],
+            current: vec![0.0; cv_channels],
+            pulse_left: vec![0; cv_channels],
+            toggled: vec![false; cv_channels],
+            generators: vec![Generator::None; cv_channels],
+        }
+    }
+
+    /// Fills interleaved frames of `channels` samples. Channels beyond the CV
+    /// channels are kept silent.
+    pub fn render<T: Sample + FromSample<f32>>(&mut self, data: &mut [T], channels: usize) {
+        let buffer_frames = data.len() / channels;
+        let start = self
+            .clock
+            .buffer_start(Instant::now(), self.frame, buffer_frames);
+
+        while self.pending.len() < self.pending.capacity()
+            && let Ok(event) = self.events.pop()
+        {
+            let due = self.frame + self.clock.frames_until(start, event.time + self.latency);
+            // Insert after any event due on the same frame so they keep their order.
+            let at = self.pending.partition_point(|&(d, _)| d <= due);
+            self.pending.insert(at, (due, event));
+        }
+
+        let mut applied = 0;
+        for (n, frame) in data.chunks_mut(channels).enumerate() {
+            let now = self.frame + n as u64;
+            while let Some(&(due, event)) = self.pending.get(applied)
+                && due <= now
+            {
+                self.apply(event);
+                applied += 1;
+            }
+
+            for (i, sample) in frame.iter_mut().enumerate() {
+                match self.generators.get_mut(i) {
+                    Some(Generator::Clock(clock)) if clock.rate > 0.0 => {
+                        // Pulse on the frame nearest to where the period ends.
+                        if clock.until < clock.rate / 2.0 {
+                            clock.until += 1.0;
+                            self.targets[i].value = clock.level;
+                            self.pulse_left[i] = clock.frames;
+                        }
+                        clock.until -= clock.rate;
+                    }
+                    Some(Generator::Lfo { lfo, rate, cycles }) => {
+                        self.targets[i].value = lfo.output(*cycles);
+                        *cycles += *rate;
+                    }
+                    Some(Generator::Envelope(envelope)) => {
+                        self.targets[i].value =
+                            envelope.level(self.clock.sample_rate) * envelope.adsr.depth;
+                        envelope.frames += 1;
+                    }
+                    _ => {}
+                }
+
+                let value = match self.targets.get(i) {
+                    Some(target) => {
+                        self.current[i] = self.rates[i].step(self.current[i], target.value);
+                        self.current[i]
+                    }
+                    None => 0.0,
+                };
+                *sample = T::from_sample(value);
+
+                if self.pulse_left.get(i).is_some_and(|&left| left > 0) {
+                    self.pulse_left[i] -= 1;
+                    if self.pulse_left[i] == 0 {
+                        self.targets[i].value = 0.0;
+                    }
+                }
+            }
+        }
+
+        self.pending.drain(..applied);
+        self.frame += buffer_frames as u64;
+    }
+
+    fn apply(&mut self, event: CvEvent) {
+        let channel = event.channel;
+        if channel >= self.targets.len() {
+            return;
+        }
+        let sample_rate = self.clock.sample_rate;
+        // Triggers and toggles are edges, they are never slewed.
+        let edge = |value: f32| CvTarget {
+            value,
+            slew: Slew::default(),
+        };
+
+        let pulse_frames = |ms: f32| ((ms * sample_rate / 1000.0) as u32).max(1);
+
+        // Generators carry their state over to their own updates, so a clock
+        // keeps its current pulse through tempo changes and an envelope its
+        // level; any other event takes the channel over.
+        let current = self.generators[channel];
+        let (target, pulse) = match event.action {
+            CvAction::Set(target) => (target, 0),
+            CvAction::Trigger { level, ms } => (edge(level), pulse_frames(ms)),
+            CvAction::Toggle { level } => {
+                self.toggled[channel] = !self.toggled[channel];
+                (edge(if self.toggled[channel] { level } else { 0.0 }), 0)
+            }
+            CvAction::Clock {
+                hz,
+                align,
+                level,
+                ms,
+            } => {
+                let mut clock = match current {
+                    Generator::Clock(clock) => clock,
+                    _ => PulseClock::default(),
+                };
+                clock.until = match align {
+                    ClockAlign::Keep => clock.until,
+                    ClockAlign::Follow(next) if clock.rate > 0.0 => {
+                        let next = next as f64;
+                        [next - 1.0, next, next + 1.0]
+                            .into_iter()
+                            .min_by(|a, b| {
+                                (a - clock.until).abs().total_cmp(&(b - clock.until).abs())
+                            })
+                            .unwrap()
+                    }
+                    ClockAlign::Restart(next) | ClockAlign::Follow(next) => next as f64,
+                };
+                clock.rate = hz.max(0.0) as f64 / sample_rate as f64;
+                clock.level = level;
+                clock.frames = pulse_frames(ms);
+                self.generators[channel] = Generator::Clock(clock);
+                self.rates[channel] = Slew::default().rates(sample_rate);
+                return;
+            }
+            CvAction::Lfo { lfo, hz, cycles } => {
+                self.generators[channel] = Generator::Lfo {
+                    lfo,
+                    rate: hz.max(0.0) as f64 / sample_rate as f64,
+                    cycles,
+                };
+                self.pulse_left[channel] = 0;
+                self.rates[channel] = Slew::default().rates(sample_rate);
+                return;
+            }
+            CvAction::Envelope { adsr, gate } => {
+                let mut envelope = match current {
+                    Generator::Envelope(envelope) => envelope,
+                    _ => EnvelopeState {
+                        adsr,
+                        gate: false,
+                        from: 0.0,
+                        frames: 0,
+                    },
+                };
+                if let Some(gate) = gate
+                    && (gate || envelope.gate)
+                {
+                    envelope.from = envelope.level(sample_rate);
+                    envelope.gate = gate;
+                    envelope.frames = 0;
+                }
+                envelope.adsr = adsr;
+                self.generators[channel] = Generator::Envelope(envelope);
+                self.pulse_left[channel] = 0;
+                self.rates[channel] = Slew::default().rates(sample_rate);
+                return;
+            }
+        };
+        self.generators[channel] = Generator::None;
+        self.pulse_left[channel] = pulse;
+        self.targets[channel] = target;
+        self.rates[channel] = self.targets[channel].slew.rates(sample_rate);
+    }
+}
+
+/// Preferred sample formats, best first. The CV values are rendered as f32 and
+/// converted, so any of these works.
+const FORMATS: &[SampleFormat] = &[
+    SampleFormat::F32,
+    SampleFormat::I32,
+    SampleFormat::I16,
+    SampleFormat::F64,
+    SampleFormat::I64,
+    SampleFormat::U32,
+    SampleFormat::U16,
+    SampleFormat::I8,
+    SampleFormat::U8,
+    SampleFormat::U64,
+];
+
+/// Preferred sample rates when none is requested.
+const RATES: &[u32] = &[48000, 44100, 96000];
+
+/// What the user asked for; `None` lets the device decide.
+#[derive(Debug, Default)]
+pub struct Request {
+    pub sample_rate: Option<u32>,
+    pub sample_format: Option<String>,
+    pub buffer_size: Option<u32>,
+}
+
+/// Picks a stream config with at least `cv_channels` channels that honors the
+/// request, preferring fewer channels, common rates and float formats.
+pub fn negotiate(
+    device: &Device,
+    cv_channels: usize,
+    request: &Request,
+) -> Result<(StreamConfig, SampleFormat), String> {
+    let mut ranges: Vec<SupportedStreamConfigRange> = device
+        .supported_output_configs()
+        .map_err(|e| e.to_string())?
+        .filter(|r| r.channels() as usize >= cv_channels)
+        .collect();
+    if ranges.is_empty() {
+        return Err(format!("no output config with {} channels", cv_channels));
+    }
+
+    if let Some(name) = &request.sample_format {
+        ranges.retain(|r| r.sample_format().to_string() == *name);
+        if ranges.is_empty() {
+            return Err(format!("sample format {} is not supported", name));
+        }
+    }
+
+    let supports = |r: &SupportedStreamConfigRange, rate: u32| {
+        (r.min_sample_rate().0..=r.max_sample_rate().0).contains(&rate)
+    };
+    if let Some(rate) = request.sample_rate {
+        ranges.retain(|r| supports(r, rate));
+        if ranges.is_empty() {
+            return Err(format!("sample rate {} is not supported", rate));
+        }
+    }
+
+    let format_rank = |r: &SupportedStreamConfigRange| {
+        FORMATS
+            .iter()
+            .position(|&f| f == r.sample_format())
+            .unwrap_or(FORMATS.len())
+    };
+    ranges.sort_by_key(|r| (r.channels(), format_rank(r)));
+    let range = ranges.remove(0);
+
+    let rate = request.sample_rate.unwrap_or_else(|| {
+        RATES
+            .iter()
+            .copied()
+            .find(|&rate| supports(&range, rate))
+            .unwrap_or(range.max_sample_rate().0)
+    });
+
+    let buffer_size = match (request.buffer_size, range.buffer_size()) {
+        (None, _) => cpal::BufferSize::Default,
+        (Some(frames), SupportedBufferSize::Range { min, max })
+            if !(*min..=*max).contains(&frames) =>
+        {
+            return Err(format!(
+                "buffer size {} is outside the supported {}..{} frames",
+                frames, min, max
+            ));
+        }
+        (Some(frames), _) => cpal::BufferSize::Fixed(frames),
+    };
+
+    let config = StreamConfig {
+        channels: range.channels(),
+        sample_rate: SampleRate(rate),
+        buffer_size,
+    };
+    Ok((config, range.sample_format()))
+}
+
+/// Builds an output stream rendering the CV outputs in the device's sample format.
+pub fn build_stream(
+    device: &Device,
+    config: &StreamConfig,
+    format: SampleFormat,
+    renderer: Renderer,
+) -> Result<Stream, String> {
+    match format {
+        SampleFormat::I8 => build::<i8>(device, config, renderer),
+        SampleFormat::I16 => build::<i16>(device, config, renderer),
+        SampleFormat::I32 => build::<i32>(device, config, renderer),
+        SampleFormat::I64 => build::<i64>(device, config, renderer),
+        SampleFormat::U8 => build::<u8>(device, config, renderer),
+        SampleFormat::U16 => build::<u16>(device, config, renderer),
+        SampleFormat::U32 => build::<u32>(device, config, renderer),
+        SampleFormat::U64 => build::<u64>(device, config, renderer),
+        SampleFormat::F32 => build::<f32>(device, config, renderer),
+        SampleFormat::F64 => build::<f64>(device, config, renderer),
+        _ => Err(format!("unsupported sample format {}", format)),
+    }
+}
+
+fn build<T>(
+    device: &Device,
+    config: &StreamConfig,
+    mut renderer: Renderer,
+) -> Result<Stream, String>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _| renderer.render(data, channels),
+            move |err| eprintln!("Audio error: {}", err),
+            None,
+        )
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::LfoShape;
+    use assert_no_alloc::{AllocDisabler, assert_no_alloc, violation_count};
+    use std::sync::{Arc, Barrier, mpsc};
+    use std::thread;
+
+    #[global_allocator]
+    static ALLOCATOR: AllocDisabler = AllocDisabler;
+
+    fn event(channel: usize, value: f32) -> CvEvent {
+        CvEvent {
+            channel,
+            action: CvAction::Set(CvTarget {
+                value,
+                slew: Slew::default(),
+            }),
+            time: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn render_does_not_allocate() {
+        let (mut producer, consumer) = cv_events();
+        let mut renderer = Renderer::new(consumer, 4, 48000.0, Duration::ZERO);
+        let mut data = vec![0i16; 6 * 256];
+        for channel in 0..4 {
+            producer.push(event(channel, 0.5)).unwrap();
+        }
+
+        let before = violation_count();
+        assert_no_alloc(|| {
+            for _ in 0..100 {
+                renderer.render(&mut data, 6);
+            }
+        });
+
+        assert_eq!(violation_count(), before);
+        assert_eq!(data[0], 0.5f32.to_sample::<i16>());
+        assert_eq!(data[5], 0);
+    }
+
+    #[test]
+    fn render_never_waits_for_the_osc_thread() {
+        let (mut producer, consumer) = cv_events();
+        let mut renderer = Renderer::new(consumer, 2, 48000.0, Duration::ZERO);
+        producer.push(event(0, 0.25)).unwrap();
+
+        // Park the OSC side in the middle of writing a batch.
+        let stalled = Arc::new(Barrier::new(2));
+        let release = stalled.clone();
+        let osc_thread = thread::spawn(move || {
+            let chunk = producer.write_chunk_uninit(2).unwrap();
+            release.wait();
+            release.wait();
+            chunk.fill_from_iter([event(0, 1.0), event(1, 1.0)]);
+        });
+        stalled.wait();
+
+        let (done, rendered) = mpsc::channel();
+        let audio_thread = thread::spawn(move || {
+            let mut data = [0f32; 2 * 64];
+            for _ in 0..1000 {
+                renderer.render(&mut data, 2);
+            }
+            done.send(data).unwrap();
+            renderer
+        });
+
+        let data = rendered
+            .recv_timeout(Duration::from_secs(5))
+            .expect("audio callback blocked on the OSC thread");
+        assert_eq!(&data[..2], &[0.25, 0.0]);
+
+        stalled.wait();
+        osc_thread.join().unwrap();
+        let mut renderer = audio_thread.join().unwrap();
+        let mut data = [0f32; 2];
+        renderer.render(&mut data, 2);
+        assert_eq!(data, [1.0, 1.0]);
+    }
+
+    #[test]
+    fn events_land_on_their_sample() {
+        let (mut producer, consumer) = cv_events();
+        // At 1 kHz a one second latency puts the event 1000 frames in.
+        let mut renderer = Renderer::new(consumer, 1, 1000.0, Duration::from_secs(1));
+        producer.push(event(0, 1.0)).unwrap();
+
+        let mut data = [0f32; 2000];
+        renderer.render(&mut data, 1);
+
+        assert_eq!(data[990], 0.0);
+        assert_eq!(data[1000], 1.0);
+    }
+
+    #[test]
+    fn trigger_pulses_for_its_length() {
+        let (mut producer, consumer) = cv_events();
+        let mut renderer = Renderer::new(consumer, 1, 1000.0, Duration::ZERO);
+        producer
+            .push(CvEvent {
+                channel: 0,
+                action: CvAction::Trigger {
+                    level: 1.0,
+                    ms: 10.0,
+                },
+                time: Instant::now(),
+            })
+            .unwrap();
+
+        let mut data = [0f32; 20];
+        renderer.render(&mut data, 1);
+
+        assert_eq!(&data[..10], &[1.0; 10]);
+        assert_eq!(&data[10..], &[0.0; 10]);
+    }
+
+    #[test]
+    fn clock_pulses_every_period() {
+        let (mut producer, consumer) = cv_events();
+        let mut renderer = Renderer::new(consumer, 1, 1000.0, Duration::ZERO);
+        producer
+            .push(CvEvent {
+                channel: 0,
+                action: CvAction::Clock {
+                    hz: 40.0,
+                    align: ClockAlign::Restart(0.0),
+                    level: 1.0,
+                    ms: 5.0,
+                },
+                time: Instant::now(),
+            })
+            .unwrap();
+
+        let mut data = [0f32; 100];
+        renderer.render(&mut data, 1);
+
+        let rising: Vec<usize> = (0..data.len())
+            .filter(|&i| data[i] == 1.0 && (i == 0 || data[i - 1] == 0.0))
+            .collect();
+        assert_eq!(rising, [0, 25, 50, 75]);
+        assert_eq!(&data[..6], &[1.0, 1.0, 1.0, 1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn envelope_follows_its_gate() {
+        let (mut producer, consumer) = cv_events();
+        let mut renderer = Renderer::new(consumer, 1, 1000.0, Duration::ZERO);
+        let envelope = |gate| CvEvent {
+            channel: 0,
+            action: CvAction::Envelope {
+                adsr: Adsr {
+                    attack_ms: 10.0,
+                    decay_ms: 10.0,
+                    sustain: 0.5,
+                    release_ms: 10.0,
+                    depth: 1.0,
+                },
+                gate: Some(gate),
+            },
+            time: Instant::now(),
+        };
+        producer.push(envelope(true)).unwrap();
+
+        let mut data = [0f32; 30];
+        renderer.render(&mut data, 1);
+        for (frame, level) in [(0, 0.0), (5, 0.5), (10, 1.0), (15, 0.75), (25, 0.5)] {
+            assert!((data[frame] - level).abs() < 1e-4, "frame {}", frame);
+        }
+
+        // The release runs down from the sustain level.
+        producer.push(envelope(false)).unwrap();
+        renderer.render(&mut data, 1);
+        for (frame, level) in [(0, 0.5), (2, 0.3), (5, 0.0), (10, 0.0)] {
+            assert!((data[frame] - level).abs() < 1e-4, "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn lfo_runs_at_its_rate() {
+        let (mut producer, consumer) = cv_events();
+        let mut renderer = Renderer::new(consumer, 1, 1000.0, Duration::ZERO);
+        producer
+            .push(CvEvent {
+                channel: 0,
+                action: CvAction::Lfo {
+                    lfo: Lfo {
+                        shape: LfoShape::Square,
+                        depth: 0.5,
+                        offset: 0.5,
+                        seed: 0,
+                    },
+                    hz: 125.0,
+                    cycles: 0.0,
+                },
+                time: Instant::now(),
+            })
+            .unwrap();
+
+        let mut data = [0f32; 16];
+        renderer.render(&mut data, 1);
+
+        assert_eq!(data, [[1.0; 4], [0.0; 4], [1.0; 4], [0.0; 4]].concat()[..]);
+    }
+}
@@ -0,0 +1,515 @@
+use crate::audio::{ClockAlign, CvAction, CvEvent, CvTarget};
+use crate::calibration::Calibration;
+use crate::clock::{Clock, MIDI_PPQN};
+use crate::config::{ClockConfig, Config, CvMode, InputKind, SharedConfig};
+use crate::generator::Generators;
+use crate::midi::{MidiLimiter, RateLimit};
+use crate::osc;
+use crate::pattern;
+use crate::pitch::{self, PitchInput};
+use crate::slew::Slew;
+use crate::sync::{Pll, SyncSource};
+use crate::voices::{VoiceAllocator, VoiceEvent};
+use midir::MidiOutputConnection;
+use rosc::{OscMessage, OscPacket, OscType, encoder};
+use rtrb::Producer;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Picks how the clock pulse lines up, given its phase on the timeline.
+type Align = fn(f32) -> ClockAlign;
+
+/// Applies incoming OSC and MIDI-input messages to the CV outputs, the MIDI
+/// connections and the outgoing OSC target.
+pub struct Bridge {
+    pub config: SharedConfig,
+    pub cv_events: Producer<CvEvent>,
+    pub midi_conns: Vec<MidiOutputConnection>,
+    /// Socket and `--osc-target` address for `osc_address` mappings.
+    pub osc_out: Option<(UdpSocket, SocketAddr)>,
+    pub midi_limiter: MidiLimiter,
+    pub calibration: Calibration,
+    /// Rebuilt whenever the `[voices]` section changes.
+    pub voices: Option<VoiceAllocator>,
+    pub clock: Clock,
+    /// The `[clock]` section the clock outputs currently follow.
+    pub clock_out: Option<ClockConfig>,
+    pub sync_pll: Pll,
+    /// MIDI clock ticks counted from the sync source since its Start, or
+    /// its last Song Position Pointer.
+    pub sync_ticks: u64,
+    /// The `[[lfo]]` and `[[envelope]]` sections, with their OSC changes.
+    pub generators: Generators,
+    pub debug: bool,
+}
+
+impl Bridge {
+    /// Applies every mapping matching the message and passes notes to the voice
+    /// allocator, transport messages to the clock and `/lfo/...` and `/env/...`
+    /// messages to the generators. CV updates from all of
+    /// its arguments are committed to the queue at once so they land in the
+    /// same buffer.
+    pub fn handle(&mut self, message: &OscMessage, time: Instant) {
+        let active = self.config.lock().unwrap().clone();
+        let mut cv_updates = Vec::new();
+        self.follow_clock_config(&active, time, &mut cv_updates);
+        self.generators.follow_config(
+            &active.lfos,
+            &active.envelopes,
+            &self.clock,
+            time,
+            &mut cv_updates,
+        );
+        self.transport(message, time, &mut cv_updates);
+        match self
+            .generators
+            .handle(message, &self.clock, time, &mut cv_updates)
+        {
+            Ok(true) if self.debug => println!("{} -> {:?}", message.addr, message.args),
+            Err(e) if self.debug => println!("{} dropped: {}", message.addr, e),
+            _ => {}
+        }
+        self.play_voices(&active, message, time, &mut cv_updates);
+        for mapping in active
+            .mappings
+            .iter()
+            .filter(|m| pattern::addresses_match(&m.address, &message.addr))
+        {
+            let arg = message.args.get(mapping.arg);
+            let Some(value) = (match arg {
+                Some(arg) => osc::arg_value(arg, mapping.input),
+                // Argument-less messages like `/kick` fire triggers and toggles.
+                None if matches!(mapping.mode, CvMode::Trigger | CvMode::Toggle) => Some(1.0),
+                None => None,
+            }) else {
+                if self.debug {
+                    println!(
+                        "{} dropped: unsupported argument {} {:?}",
+                        message.addr, mapping.arg, arg
+                    );
+                }
+                continue;
+            };
+
+            let shaped = mapping.shape(value);
+            let audio_val = match (mapping.mode, mapping.cv_channel) {
+                (CvMode::Pitch, Some(channel)) => {
+                    let volts = pitch::volts(
+                        value,
+                        mapping.pitch_input,
+                        mapping.pitch_scale,
+                        mapping.root_note,
+                    );
+                    self.calibration.sample(channel, volts)
+                }
+                _ => mapping.cv_value(shaped),
+            };
+
+            let high = mapping.cv_range.1;
+            let action = match mapping.mode {
+                CvMode::Value | CvMode::Pitch => Some(CvAction::Set(CvTarget {
+                    value: audio_val,
+                    slew: mapping.slew,
+                })),
+                CvMode::Gate => Some(CvAction::Set(CvTarget {
+                    value: if value != 0.0 { high } else { 0.0 },
+                    slew: Slew::default(),
+                })),
+                CvMode::Trigger if value != 0.0 => Some(CvAction::Trigger {
+                    level: high,
+                    ms: mapping.trigger_ms,
+                }),
+                CvMode::Toggle if value != 0.0 => Some(CvAction::Toggle { level: high }),
+                CvMode::Trigger | CvMode::Toggle => None,
+            };
+            if let (Some(channel), Some(action)) = (mapping.cv_channel, action) {
+                cv_updates.push(CvEvent {
+                    channel,
+                    action,
+                    time,
+                });
+            }
+
+            let mut midi_messages = Vec::new();
+            if let Some(midi) = mapping.midi {
+                let now = Instant::now();
+                for &port in &mapping.midi_ports {
+                    let messages = midi.messages(shaped);
+                    let Some(messages) =
+                        self.midi_limiter
+                            .submit(port, midi, mapping.midi_limit, messages, now)
+                    else {
+                        continue;
+                    };
+                    for midi_message in &messages {
+                        self.midi_conns[port].send(midi_message).unwrap();
+                    }
+                    midi_messages.extend(messages);
+                }
+            }
+
+            if let (Some(addr), Some((socket, target))) = (&mapping.osc_address, &self.osc_out) {
+                let packet = OscPacket::Message(OscMessage {
+                    addr: addr.clone(),
+                    args: vec![OscType::Float(mapping.osc_value(shaped))],
+                });
+                match encoder::encode(&packet) {
+                    Ok(buf) => {
+                        if let Err(e) = socket.send_to(&buf, target) {
+                            eprintln!("Failed to send {} to {}: {}", addr, target, e);
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to encode {}: {}", addr, e),
+                }
+            }
+
+            if self.debug {
+                println!(
+                    "{}[{}] -> Channel {}: Audio {}, MIDI {:02X?}",
+                    message.addr,
+                    mapping.arg,
+                    mapping
+                        .cv_channel
+                        .map_or("-".to_string(), |c| (c + 1).to_string()),
+                    audio_val,
+                    midi_messages
+                );
+            }
+        }
+
+        self.commit(cv_updates, &message.addr);
+    }
+
+    fn commit(&mut self, cv_updates: Vec<CvEvent>, source: &str) {
+        if cv_updates.is_empty() {
+            return;
+        }
+        match self.cv_events.write_chunk_uninit(cv_updates.len()) {
+            Ok(chunk) => {
+                chunk.fill_from_iter(cv_updates);
+            }
+            Err(_) => eprintln!("CV event queue full, dropped {}", source),
+        }
+    }
+
+    /// Moves the clock outputs over when the `[clock]` section changed. The old
+    /// CV channel drops to 0, a new one joins a running clock in phase.
+    fn follow_clock_config(
+        &mut self,
+        active: &Config,
+        time: Instant,
+        cv_updates: &mut Vec<CvEvent>,
+    ) {
+        if self.clock_out == active.clock {
+            return;
+        }
+        if let Some(channel) = self.clock_out.take().and_then(|old| old.cv_channel) {
+            cv_updates.push(CvEvent {
+                channel,
+                action: CvAction::Set(CvTarget::default()),
+                time,
+            });
+        }
+        self.clock_out = active.clock.clone();
+        if self.clock.running {
+            cv_updates.extend(self.cv_clock(ClockAlign::Restart, time));
+        }
+    }
+
+    /// Follows `/tempo <bpm>`, `/start`, `/stop` and `/continue [<beat>]`, or
+    /// the sync source picked in `[clock]`, sending the matching MIDI transport
+    /// messages and clock pulse changes.
+    fn transport(&mut self, message: &OscMessage, time: Instant, cv_updates: &mut Vec<CvEvent>) {
+        let sync = self
+            .clock_out
+            .as_ref()
+            .map_or(SyncSource::Internal, |out| out.sync);
+        let arg = |i: usize| {
+            message
+                .args
+                .get(i)
+                .and_then(|arg| osc::arg_value(arg, InputKind::Auto))
+        };
+        let (midi_messages, align): (Vec<Vec<u8>>, Align) = match (message.addr.as_str(), sync) {
+            ("/tempo", SyncSource::Internal) => {
+                let Some(bpm) = arg(0).filter(|bpm| bpm.is_finite() && *bpm > 0.0) else {
+                    if self.debug {
+                        println!("/tempo dropped: expected <bpm>");
+                    }
+                    return;
+                };
+                self.clock.set_tempo(bpm, time);
+                (Vec::new(), |_| ClockAlign::Keep)
+            }
+            ("/start", _) | ("/midi/start", SyncSource::Midi) => {
+                if message.addr == "/midi/start" {
+                    // The first MIDI clock after Start is beat 0.
+                    self.sync_pll
+                        .shift(-(self.sync_ticks as f64) / MIDI_PPQN as f64);
+                    self.sync_ticks = 0;
+                }
+                self.clock.start(time);
+                (vec![vec![0xFA]], ClockAlign::Restart)
+            }
+            ("/stop", _) | ("/midi/stop", SyncSource::Midi) => {
+                self.clock.stop();
+                (vec![vec![0xFC]], |_| ClockAlign::Keep)
+            }
+            ("/continue", _) | ("/midi/continue", SyncSource::Midi) => {
+                // Song Position Pointer counts 16th notes, so the position
+                // rounds down to the previous one. Positions past the largest
+                // pointer saturate rather than wrap.
+                let sixteenths = match sync {
+                    SyncSource::Midi => {
+                        Some(u16::try_from(self.sync_ticks / 6).unwrap_or(u16::MAX))
+                    }
+                    _ => arg(0).map(|beat| (beat.max(0.0) * 4.0) as u16),
+                };
+                let position = self.clock.resume(sixteenths, time);
+                let spp = vec![0xF2, (position & 0x7F) as u8, (position >> 7) as u8];
+                (vec![spp, vec![0xFB]], ClockAlign::Restart)
+            }
+            ("/midi/position", SyncSource::Midi) => {
+                let Some(position) = arg(0) else {
+                    return;
+                };
+                // Bounded to what a Song Position Pointer can carry.
+                let ticks = position.clamp(0.0, 0x3FFF as f32) as u64 * 6;
+                self.sync_pll
+                    .shift((ticks as f64 - self.sync_ticks as f64) / MIDI_PPQN as f64);
+                self.sync_ticks = ticks;
+                return;
+            }
+            ("/midi/clock", SyncSource::Midi) => {
+                let beat = self.sync_ticks as f64 / MIDI_PPQN as f64;
+                self.sync_ticks += 1;
+                self.follow(time, beat, None, cv_updates);
+                return;
+            }
+            ("/sync/beat", SyncSource::Osc) => {
+                // A tempo, when sent, has to be usable too.
+                let bpm = arg(1).filter(|bpm| *bpm > 0.0);
+                let Some(beat) = arg(0).filter(|_| message.args.len() < 2 || bpm.is_some()) else {
+                    if self.debug {
+                        println!("/sync/beat dropped: expected <beat> [<bpm>]");
+                    }
+                    return;
+                };
+                self.follow(time, beat as f64, bpm.map(f64::from), cv_updates);
+                return;
+            }
+            _ => return,
+        };
+
+        if let Some(out) = &self.clock_out {
+            for &port in &out.midi_ports {
+                for midi_message in &midi_messages {
+                    self.midi_conns[port].send(midi_message).unwrap();
+                }
+            }
+        }
+        cv_updates.extend(self.cv_clock(align, time));
+        // Synced LFOs jump along with the timeline.
+        self.generators.sync(&self.clock, time, true, cv_updates);
+        if self.debug {
+            println!(
+                "{} -> Clock {} BPM, {}, tick {}",
+                message.addr,
+                self.clock.bpm,
+                if self.clock.running {
+                    "running"
+                } else {
+                    "stopped"
+                },
+                self.clock.ticks
+            );
+        }
+    }
+
+    /// Feeds an external beat to the phase-locked loop and lines the clock
+    /// and its pulse up with the result.
+    fn follow(
+        &mut self,
+        time: Instant,
+        beat: f64,
+        hint_bpm: Option<f64>,
+        cv_updates: &mut Vec<CvEvent>,
+    ) {
+        let Some((beat, bpm)) = self.sync_pll.observe(time, beat, hint_bpm) else {
+            return;
+        };
+        self.clock.follow(beat, bpm as f32, time);
+        if self.clock.running {
+            cv_updates.extend(self.cv_clock(ClockAlign::Follow, time));
+        }
+        self.generators.sync(&self.clock, time, false, cv_updates);
+    }
+
+    /// The clock pulse for the current tempo and transport state, lined up
+    /// with the timeline as `align` says.
+    fn cv_clock(&self, align: Align, time: Instant) -> Option<CvEvent> {
+        let out = self.clock_out.as_ref()?;
+        Some(CvEvent {
+            channel: out.cv_channel?,
+            action: CvAction::Clock {
+                hz: if self.clock.running {
+                    self.clock.pulse_hz(out.ppqn)
+                } else {
+                    0.0
+                },
+                align: align(self.clock.pulse_phase(out.ppqn, time)),
+                level: 1.0,
+                ms: out.pulse_ms,
+            },
+            time,
+        })
+    }
+
+    /// Allocates voices for a note message, after replacing the allocator if
+    /// the `[voices]` section changed since the last one.
+    fn play_voices(
+        &mut self,
+        active: &Config,
+        message: &OscMessage,
+        time: Instant,
+        cv_updates: &mut Vec<CvEvent>,
+    ) {
+        if self.voices.as_ref().map(|v| &v.config) != active.voices.as_ref() {
+            if let Some(mut old) = self.voices.take() {
+                let released = old.release_all();
+                self.voice_events(&old, released, time, cv_updates);
+            }
+            self.voices = active.voices.clone().map(VoiceAllocator::new);
+        }
+        let Some(mut voices) = self.voices.take() else {
+            return;
+        };
+        if pattern::addresses_match(&voices.config.address, &message.addr) {
+            let arg = |i: usize| {
+                message
+                    .args
+                    .get(i)
+                    .and_then(|arg| osc::arg_value(arg, InputKind::Auto))
+            };
+            match (arg(0), arg(1)) {
+                (Some(note), Some(velocity)) => {
+                    let changes = voices.note(note, velocity);
+                    self.voice_events(&voices, changes, time, cv_updates);
+                }
+                _ if self.debug => {
+                    println!("{} dropped: expected <note> <velocity>", message.addr)
+                }
+                _ => {}
+            }
+        }
+        self.voices = Some(voices);
+    }
+
+    fn voice_events(
+        &self,
+        voices: &VoiceAllocator,
+        changes: Vec<(usize, VoiceEvent)>,
+        time: Instant,
+        cv_updates: &mut Vec<CvEvent>,
+    ) {
+        let config = &voices.config;
+        let set = |channel: usize, value: f32, slew: Slew| CvEvent {
+            channel,
+            action: CvAction::Set(CvTarget { value, slew }),
+            time,
+        };
+        for (voice, event) in changes {
+            let group = config.groups[voice];
+            match event {
+                VoiceEvent::On { note, velocity } => {
+                    let volts =
+                        pitch::volts(note, PitchInput::Note, config.pitch_scale, config.root_note);
+                    let pitch = self.calibration.sample(group.pitch, volts);
+                    cv_updates.push(set(group.pitch, pitch, config.glide));
+                    cv_updates.push(set(group.gate, 1.0, Slew::default()));
+                    if let Some(channel) = group.velocity {
+                        cv_updates.push(set(channel, velocity.clamp(0.0, 1.0), Slew::default()));
+                    }
+                }
+                VoiceEvent::Off => cv_updates.push(set(group.gate, 0.0, Slew::default())),
+            }
+            if self.debug {
+                println!("Voice {} -> {:?}", voice + 1, event);
+            }
+        }
+    }
+
+    /// Sends the MIDI clock ticks that have come due, after following any
+    /// change to the `[clock]` section.
+    pub fn flush_clock(&mut self) {
+        let now = Instant::now();
+        let active = self.config.lock().unwrap().clone();
+        let mut cv_updates = Vec::new();
+        self.follow_clock_config(&active, now, &mut cv_updates);
+        self.commit(cv_updates, "clock");
+
+        let ticks = self.clock.take_ticks(now);
+        if let Some(out) = &self.clock_out {
+            for _ in 0..ticks {
+                for &port in &out.midi_ports {
+                    self.midi_conns[port].send(&[0xF8]).unwrap();
+                }
+            }
+        }
+    }
+
+    pub fn time_until_clock(&self) -> Option<Duration> {
+        self.clock.time_until_tick(Instant::now())
+    }
+
+    /// Sends the generator values due for MIDI and keeps the LFO phases in the
+    /// audio callback in step, after following any change to their sections.
+    pub fn flush_generators(&mut self) {
+        let now = Instant::now();
+        let active = self.config.lock().unwrap().clone();
+        let mut cv_updates = Vec::new();
+        self.generators.follow_config(
+            &active.lfos,
+            &active.envelopes,
+            &self.clock,
+            now,
+            &mut cv_updates,
+        );
+        self.generators
+            .sync(&self.clock, now, false, &mut cv_updates);
+        self.commit(cv_updates, "generators");
+
+        // The generators pace themselves, only repeats are dropped here.
+        let limit = RateLimit {
+            min_interval: None,
+            dedup: true,
+        };
+        for (midi, ports, value) in self.generators.midi_due(&self.clock, now) {
+            for port in ports {
+                let messages = midi.messages(value);
+                let Some(messages) = self.midi_limiter.submit(port, midi, limit, messages, now)
+                else {
+                    continue;
+                };
+                for midi_message in &messages {
+                    self.midi_conns[port].send(midi_message).unwrap();
+                }
+            }
+        }
+    }
+
+    pub fn time_until_generators(&self) -> Option<Duration> {
+        self.generators.time_until_next(Instant::now())
+    }
+
+    /// Sends rate-limited MIDI values whose turn has come.
+    pub fn flush_midi(&mut self) {
+        for (port, midi_message) in self.midi_limiter.take_due(Instant::now()) {
+            self.midi_conns[port].send(&midi_message).unwrap();
+        }
+    }
+
+    pub fn time_until_midi(&self) -> Option<Duration> {
+        self.midi_limiter.time_until_next(Instant::now())
+    }
+}
@@ -0,0 +1,111 @@
+use crate::audio::{CvAction, CvEvent, CvTarget};
+use crate::calibration::{Calibration, ChannelCalibration};
+use crate::slew::Slew;
+use rtrb::Producer;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+/// Largest reference sample, leaving headroom below the interface's clip point.
+const MAX_REFERENCE: f32 = 0.8;
+
+/// Interactive calibration: holds each channel at a series of reference
+/// samples, asks for the voltage measured at the output and stores the
+/// resulting points in the profile at `output`.
+pub fn run(
+    mut cv_events: Producer<CvEvent>,
+    cv_channels: usize,
+    selected: &[usize],
+    steps: usize,
+    output: &Path,
+) {
+    let mut calibration = if output.exists() {
+        Calibration::load(output, cv_channels).unwrap_or_else(|e| {
+            eprintln!("Invalid calibration {}: {}", output.display(), e);
+            process::exit(1);
+        })
+    } else {
+        Calibration::uncalibrated(cv_channels)
+    };
+
+    let channels: Vec<usize> = if selected.is_empty() {
+        (1..=cv_channels).collect()
+    } else {
+        selected.to_vec()
+    };
+    if let Some(channel) = channels.iter().find(|&&c| !(1..=cv_channels).contains(&c)) {
+        eprintln!("Channel {} is outside 1-{}", channel, cv_channels);
+        process::exit(1);
+    }
+    let steps = steps.max(2);
+    let references: Vec<f32> = (0..steps)
+        .map(|i| -MAX_REFERENCE + 2.0 * MAX_REFERENCE * i as f32 / (steps - 1) as f32)
+        .collect();
+
+    println!("\n** Calibration **");
+    println!("Measure each output with a voltmeter and type the reading in volts.");
+    println!("Leave a reading empty to skip it, type `s` to skip the channel.\n");
+
+    let mut set = |channel: usize, value: f32| {
+        let event = CvEvent {
+            channel: channel - 1,
+            action: CvAction::Set(CvTarget {
+                value,
+                slew: Slew::default(),
+            }),
+            time: Instant::now(),
+        };
+        if cv_events.push(event).is_err() {
+            eprintln!("CV event queue full");
+        }
+    };
+
+    let mut lines = io::stdin().lock().lines();
+    'channels: for &channel in &channels {
+        let mut points = Vec::new();
+        for &sample in &references {
+            set(channel, sample);
+            print!("Channel {} at sample {:+.3}: ", channel, sample);
+            io::stdout().flush().unwrap();
+
+            let Some(Ok(line)) = lines.next() else {
+                // Input closed, keep what was measured so far.
+                set(channel, 0.0);
+                break 'channels;
+            };
+            match line.trim() {
+                "" => continue,
+                "s" => {
+                    points.clear();
+                    break;
+                }
+                reading => match reading.parse::<f32>() {
+                    Ok(volts) if volts.is_finite() => points.push([volts, sample]),
+                    _ => println!("Not a voltage, skipping this reading"),
+                },
+            }
+        }
+        set(channel, 0.0);
+
+        points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        points.dedup_by(|a, b| a[0] == b[0]);
+        if points.len() < 2 {
+            println!("Channel {} needs two readings, left unchanged\n", channel);
+            continue;
+        }
+        calibration.set(ChannelCalibration {
+            points,
+            ..ChannelCalibration::uncalibrated(channel)
+        });
+        println!("Channel {} calibrated\n", channel);
+    }
+
+    match calibration.save(output) {
+        Ok(()) => println!("Calibration written to {}", output.display()),
+        Err(e) => {
+            eprintln!("Failed to write {}: {}", output.display(), e);
+            process::exit(1);
+        }
+    }
+}
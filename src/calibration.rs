@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Full-scale output of a typical DC-coupled Eurorack interface.
+const DEFAULT_FULL_SCALE_VOLTS: f32 = 10.0;
+
+/// Converts volts into sample values for one CV channel.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelCalibration {
+    /// 1-based CV channel.
+    pub channel: usize,
+    /// Volts produced by a full-scale sample of 1.0.
+    #[serde(default = "default_full_scale_volts")]
+    pub full_scale_volts: f32,
+    /// Volts added after scaling, to cancel a DC offset.
+    #[serde(default)]
+    pub offset: f32,
+    /// Multiplier correcting the tracking of the channel and oscillator.
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    /// Measured `[volts, sample]` pairs. When given they replace the linear
+    /// model and are interpolated piecewise.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub points: Vec<[f32; 2]>,
+}
+
+fn default_full_scale_volts() -> f32 {
+    DEFAULT_FULL_SCALE_VOLTS
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+impl ChannelCalibration {
+    pub fn uncalibrated(channel: usize) -> Self {
+        ChannelCalibration {
+            channel,
+            full_scale_volts: DEFAULT_FULL_SCALE_VOLTS,
+            offset: 0.0,
+            scale: 1.0,
+            points: Vec::new(),
+        }
+    }
+
+    /// The sample value producing `volts` on this channel.
+    pub fn sample(&self, volts: f32) -> f32 {
+        let sample = if self.points.len() >= 2 {
+            // Extrapolate from the outer segments beyond the measured points.
+            let i = self
+                .points
+                .windows(2)
+                .position(|w| volts < w[1][0])
+                .unwrap_or(self.points.len() - 2);
+            let [[v0, s0], [v1, s1]] = [self.points[i], self.points[i + 1]];
+            s0 + (volts - v0) * (s1 - s0) / (v1 - v0)
+        } else {
+            (volts * self.scale + self.offset) / self.full_scale_volts
+        };
+        sample.clamp(-1.0, 1.0)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RawCalibration {
+    #[serde(default)]
+    channel: Vec<ChannelCalibration>,
+}
+
+/// Calibration for every CV channel, indexed by zero-based channel.
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    channels: Vec<ChannelCalibration>,
+}
+
+impl Calibration {
+    pub fn uncalibrated(cv_channels: usize) -> Self {
+        Calibration {
+            channels: (1..=cv_channels)
+                .map(ChannelCalibration::uncalibrated)
+                .collect(),
+        }
+    }
+
+    pub fn load(path: &Path, cv_channels: usize) -> Result<Self, String> {
+        let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&source, cv_channels)
+    }
+
+    fn parse(source: &str, cv_channels: usize) -> Result<Self, String> {
+        let raw: RawCalibration = toml::from_str(source).map_err(|e| e.to_string())?;
+
+        let mut calibration = Calibration::uncalibrated(cv_channels);
+        for mut channel in raw.channel {
+            if !(1..=cv_channels).contains(&channel.channel) {
+                return Err(format!(
+                    "channel {} is outside 1-{}",
+                    channel.channel, cv_channels
+                ));
+            }
+            let values = [channel.full_scale_volts, channel.offset, channel.scale];
+            if !values
+                .iter()
+                .chain(channel.points.iter().flatten())
+                .all(|v| v.is_finite())
+            {
+                return Err(format!(
+                    "channel {}: calibration values must be finite numbers",
+                    channel.channel
+                ));
+            }
+            if channel.full_scale_volts <= 0.0 || channel.scale == 0.0 {
+                return Err(format!(
+                    "channel {}: `full_scale_volts` and `scale` must be non-zero",
+                    channel.channel
+                ));
+            }
+            channel.points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+            if channel.points.windows(2).any(|w| w[0][0] == w[1][0]) {
+                return Err(format!(
+                    "channel {}: two points share the same voltage",
+                    channel.channel
+                ));
+            }
+            calibration.set(channel);
+        }
+        Ok(calibration)
+    }
+
+    /// Replaces the calibration of the channel `channel` refers to.
+    pub fn set(&mut self, channel: ChannelCalibration) {
+        let index = channel.channel - 1;
+        self.channels[index] = channel;
+    }
+
+    /// Writes the profile, leaving out channels that were never calibrated.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let raw = RawCalibration {
+            channel: self
+                .channels
+                .iter()
+                .filter(|c| **c != ChannelCalibration::uncalibrated(c.channel))
+                .cloned()
+                .collect(),
+        };
+        let source = toml::to_string(&raw).map_err(|e| e.to_string())?;
+        fs::write(path, source).map_err(|e| e.to_string())
+    }
+
+    /// The sample value producing `volts` on a zero-based channel.
+    pub fn sample(&self, channel: usize, volts: f32) -> f32 {
+        self.channels[channel].sample(volts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pitch::{self, PitchInput, PitchScale};
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn notes_and_hz_become_volts() {
+        let volts = |value, input, scale| pitch::volts(value, input, scale, 60.0);
+        let per_octave = PitchScale::VoltPerOctave;
+        assert!(close(volts(60.0, PitchInput::Note, per_octave), 0.0));
+        assert!(close(volts(72.0, PitchInput::Note, per_octave), 1.0));
+        assert!(close(volts(54.0, PitchInput::Note, per_octave), -0.5));
+        // Middle C is 261.63 Hz.
+        assert!(close(volts(523.2511, PitchInput::Hz, per_octave), 1.0));
+        // Nonsense frequencies still give a finite voltage.
+        assert!(volts(0.0, PitchInput::Hz, per_octave).is_finite());
+
+        // Hz/V doubles the voltage per octave, with the root at 1 V.
+        let hz_per_volt = PitchScale::HzPerVolt;
+        assert!(close(volts(60.0, PitchInput::Note, hz_per_volt), 1.0));
+        assert!(close(volts(72.0, PitchInput::Note, hz_per_volt), 2.0));
+        assert!(close(volts(48.0, PitchInput::Note, hz_per_volt), 0.5));
+    }
+
+    #[test]
+    fn the_linear_model_scales_and_offsets() {
+        let mut channel = ChannelCalibration::uncalibrated(1);
+        assert!(close(channel.sample(1.0), 0.1));
+        channel.scale = 1.02;
+        channel.offset = -0.05;
+        assert!(close(channel.sample(1.0), 0.097));
+        // Samples clip at full scale.
+        assert_eq!(channel.sample(20.0), 1.0);
+        assert_eq!(channel.sample(-20.0), -1.0);
+    }
+
+    #[test]
+    fn measured_points_interpolate_and_extrapolate() {
+        let mut channel = ChannelCalibration::uncalibrated(1);
+        channel.points = vec![[0.0, 0.0], [1.0, 0.1], [2.0, 0.25]];
+        assert!(close(channel.sample(0.5), 0.05));
+        assert!(close(channel.sample(1.0), 0.1));
+        assert!(close(channel.sample(1.5), 0.175));
+        // Beyond the points the outer segments carry on.
+        assert!(close(channel.sample(-1.0), -0.1));
+        assert!(close(channel.sample(3.0), 0.4));
+        assert_eq!(channel.sample(10.0), 1.0);
+    }
+
+    #[test]
+    fn values_must_be_finite() {
+        let profile = |entry: &str| format!("[[channel]]\nchannel = 2\n{}\n", entry);
+        assert!(Calibration::parse(&profile("offset = 0.1"), 4).is_ok());
+        for entry in [
+            "offset = nan",
+            "scale = inf",
+            "full_scale_volts = inf",
+            "points = [[0.0, 0.0], [1.0, nan]]",
+        ] {
+            assert_eq!(
+                Calibration::parse(&profile(entry), 4).unwrap_err(),
+                "channel 2: calibration values must be finite numbers",
+                "{}",
+                entry
+            );
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! The internal tempo clock and transport, counted in MIDI clock ticks.
+
+use std::time::{Duration, Instant};
+
+/// MIDI clock resolution in ticks per quarter note.
+pub const MIDI_PPQN: u32 = 24;
+
+/// MIDI clock ticks per Song Position Pointer step, a 16th note.
+const TICKS_PER_SIXTEENTH: u64 = 6;
+
+/// Tempo range accepted from `/tempo` and external sync.
+pub const MIN_BPM: f32 = 1.0;
+pub const MAX_BPM: f32 = 999.0;
+
+/// A beat timeline running at `bpm`. Tempo changes and sync corrections
+/// re-anchor the timeline, so the song position never jumps.
+pub struct Clock {
+    pub bpm: f32,
+    pub running: bool,
+    /// MIDI clock ticks since the start of the song, which is also the index
+    /// of the next tick to send.
+    pub ticks: u64,
+    /// A point on the timeline, in quarter-note beats.
+    anchor: (Instant, f64),
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock {
+            bpm: 120.0,
+            running: false,
+            ticks: 0,
+            anchor: (Instant::now(), 0.0),
+        }
+    }
+}
+
+impl Clock {
+    /// The beat on the timeline at `time`.
+    pub fn beat_at(&self, time: Instant) -> f64 {
+        let (at, beat) = self.anchor;
+        let elapsed = if time >= at {
+            (time - at).as_secs_f64()
+        } else {
+            -(at - time).as_secs_f64()
+        };
+        beat + elapsed * self.bpm as f64 / 60.0
+    }
+
+    /// Changes the tempo at `time`.
+    pub fn set_tempo(&mut self, bpm: f32, time: Instant) {
+        self.anchor = (time, self.beat_at(time));
+        self.bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+    }
+
+    /// Lines the timeline up with an external beat and tempo at `time`. Only
+    /// the phase within a beat is taken over; the song position stays ours.
+    pub fn follow(&mut self, beat: f64, bpm: f32, time: Instant) {
+        let offset = (self.beat_at(time) - beat).round();
+        self.anchor = (time, beat + offset);
+        self.bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+    }
+
+    /// Starts from the top, the first tick is due at `time`.
+    pub fn start(&mut self, time: Instant) {
+        self.ticks = 0;
+        self.running = true;
+        self.anchor = (time, 0.0);
+    }
+
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    /// Resumes at `time` from `sixteenths` into the song, or from where it
+    /// stopped rounded down to a 16th note, and returns the Song Position
+    /// Pointer to send along.
+    pub fn resume(&mut self, sixteenths: Option<u16>, time: Instant) -> u16 {
+        let position = sixteenths
+            .map(u64::from)
+            .unwrap_or(self.ticks / TICKS_PER_SIXTEENTH)
+            .min(0x3FFF);
+        self.ticks = position * TICKS_PER_SIXTEENTH;
+        self.running = true;
+        self.anchor = (time, self.ticks as f64 / MIDI_PPQN as f64);
+        position as u16
+    }
+
+    /// Counts the ticks that have come due, advancing the song position.
+    pub fn take_ticks(&mut self, now: Instant) -> u32 {
+        if !self.running {
+            return 0;
+        }
+        let beat = self.beat_at(now);
+        let mut due = 0;
+        while self.ticks as f64 / MIDI_PPQN as f64 <= beat {
+            due += 1;
+            self.ticks += 1;
+        }
+        due
+    }
+
+    /// Time left until the next tick, `None` while stopped.
+    pub fn time_until_tick(&self, now: Instant) -> Option<Duration> {
+        let beats = self.ticks as f64 / MIDI_PPQN as f64 - self.beat_at(now);
+        self.running
+            .then(|| Duration::from_secs_f64((beats * 60.0 / self.bpm as f64).max(0.0)))
+    }
+
+    /// Pulses per second at `ppqn` pulses per quarter note.
+    pub fn pulse_hz(&self, ppqn: u32) -> f32 {
+        self.bpm / 60.0 * ppqn as f32
+    }
+
+    /// The fraction of a pulse at `ppqn` left at `time` until the timeline
+    /// reaches the next pulse, 0 when it sits on one.
+    pub fn pulse_phase(&self, ppqn: u32, time: Instant) -> f32 {
+        let pulses = self.beat_at(time) * ppqn as f64;
+        (pulses.ceil() - pulses) as f32
+    }
+}
@@ -0,0 +1,1229 @@
+use crate::curve::Curve;
+use crate::generator::{Adsr, EnvelopeConfig, GeneratorOutput, Lfo, LfoConfig, LfoShape};
+use crate::midi::{MidiKind, MidiTarget, MidiType, RateLimit};
+use crate::pattern;
+use crate::pitch::{PitchInput, PitchScale};
+use crate::slew::{Slew, SlewMode};
+use crate::sync::SyncSource;
+use crate::voices::{Priority, VoiceConfig, VoiceGroup};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use toml::Spanned;
+
+/// Bounds of `midi_max_rate`, in messages per second.
+const MIN_MIDI_RATE: f32 = 0.01;
+const MAX_MIDI_RATE: f32 = 10_000.0;
+
+/// The active config, swapped as a whole on reload.
+pub type SharedConfig = Arc<Mutex<Arc<Config>>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub mappings: Vec<Mapping>,
+    pub voices: Option<VoiceConfig>,
+    pub clock: Option<ClockConfig>,
+    pub lfos: Vec<LfoConfig>,
+    pub envelopes: Vec<EnvelopeConfig>,
+}
+
+/// Where the clock takes its tempo from and where it goes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockConfig {
+    pub sync: SyncSource,
+    /// MIDI outputs receiving 24 PPQN clock, Start/Stop/Continue and Song
+    /// Position Pointer.
+    pub midi_ports: Vec<usize>,
+    /// Zero-based CV channel for the clock pulse.
+    pub cv_channel: Option<usize>,
+    /// Pulses per quarter note on the CV channel.
+    pub ppqn: u32,
+    pub pulse_ms: f32,
+}
+
+/// One OSC address or address pattern routed to a CV channel, a MIDI message
+/// and/or an outgoing OSC message.
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    pub address: String,
+    /// Index of the OSC argument the mapping reads.
+    pub arg: usize,
+    /// Zero-based CV output channel.
+    pub cv_channel: Option<usize>,
+    pub midi: Option<MidiTarget>,
+    /// Indices of the MIDI outputs the message goes to.
+    pub midi_ports: Vec<usize>,
+    pub midi_limit: RateLimit,
+    /// Address of the OSC message sent to `--osc-target`.
+    pub osc_address: Option<String>,
+    /// Range the shaped value is scaled into for the outgoing OSC message.
+    pub osc_range: (f32, f32),
+    pub input: InputKind,
+    /// Incoming value range, normalized to 0..1 before conversion.
+    pub range: (f32, f32),
+    pub curve: Curve,
+    /// Output range in full-scale sample units, -1..1.
+    pub cv_range: (f32, f32),
+    pub slew: Slew,
+    pub mode: CvMode,
+    /// Length of the pulse in `trigger` mode.
+    pub trigger_ms: f32,
+    pub pitch_input: PitchInput,
+    pub pitch_scale: PitchScale,
+    /// MIDI note at 0 V for 1V/oct, at 1 V for Hz/V.
+    pub root_note: f32,
+}
+
+/// How a mapping drives its CV channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CvMode {
+    /// Follows the value.
+    #[default]
+    Value,
+    /// High while the value is non-zero.
+    Gate,
+    /// A fixed-length pulse on every non-zero value.
+    Trigger,
+    /// Flips between low and high on every non-zero value.
+    Toggle,
+    /// Oscillator pitch from note numbers or Hz, calibrated per channel.
+    Pitch,
+}
+
+/// What the mappings can be routed to.
+#[derive(Debug, Clone)]
+pub struct Outputs {
+    pub cv_channels: usize,
+    /// The `--midi-device` and then `--virtual-midi` names, in the order the
+    /// ports were opened.
+    pub midi_ports: Vec<String>,
+    /// Whether an `--osc-target` was given.
+    pub osc_out: bool,
+}
+
+/// How the OSC argument is coerced into a value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputKind {
+    /// Ints, longs, floats and doubles as-is, bools as 0/1, OSC MIDI messages
+    /// by their data bytes scaled to 0..1.
+    #[default]
+    Auto,
+    /// Like `auto`, but any non-zero value becomes 1. Unlike the `gate` mode,
+    /// this only changes the value; range, curve and outputs still apply.
+    Boolean,
+}
+
+impl Mapping {
+    pub fn normalize(&self, value: f32) -> f32 {
+        let (min, max) = self.range;
+        ((value - min) / (max - min)).clamp(0.0, 1.0)
+    }
+
+    /// Normalizes an incoming value and applies the response curve.
+    pub fn shape(&self, value: f32) -> f32 {
+        self.curve.apply(self.normalize(value))
+    }
+
+    /// Scales a shaped 0..1 value into the CV output range.
+    pub fn cv_value(&self, shaped: f32) -> f32 {
+        let (low, high) = self.cv_range;
+        low + (high - low) * shaped
+    }
+
+    /// Scales a shaped 0..1 value into the outgoing OSC range.
+    pub fn osc_value(&self, shaped: f32) -> f32 {
+        let (low, high) = self.osc_range;
+        low + (high - low) * shaped
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    mapping: Vec<Spanned<RawMapping>>,
+    voices: Option<Spanned<RawVoices>>,
+    clock: Option<Spanned<RawClock>>,
+    #[serde(default)]
+    lfo: Vec<Spanned<RawLfo>>,
+    #[serde(default)]
+    envelope: Vec<Spanned<RawEnvelope>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLfo {
+    #[serde(default)]
+    shape: LfoShape,
+    #[serde(default = "default_lfo_rate")]
+    rate: f32,
+    #[serde(default)]
+    sync: bool,
+    #[serde(default = "default_depth")]
+    depth: f32,
+    #[serde(default)]
+    offset: f32,
+    #[serde(default)]
+    phase: f32,
+    cv_channel: Option<usize>,
+    midi_cc: Option<u8>,
+    #[serde(default = "default_midi_channel")]
+    midi_channel: u8,
+    midi_port: Option<OneOrMany>,
+    #[serde(default = "default_generator_midi_rate")]
+    midi_max_rate: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEnvelope {
+    #[serde(default = "default_attack_ms")]
+    attack_ms: f32,
+    #[serde(default = "default_decay_ms")]
+    decay_ms: f32,
+    #[serde(default = "default_sustain")]
+    sustain: f32,
+    #[serde(default = "default_release_ms")]
+    release_ms: f32,
+    #[serde(default = "default_depth")]
+    depth: f32,
+    cv_channel: Option<usize>,
+    midi_cc: Option<u8>,
+    #[serde(default = "default_midi_channel")]
+    midi_channel: u8,
+    midi_port: Option<OneOrMany>,
+    #[serde(default = "default_generator_midi_rate")]
+    midi_max_rate: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawClock {
+    #[serde(default)]
+    sync: SyncSource,
+    midi_port: Option<OneOrMany>,
+    cv_channel: Option<usize>,
+    #[serde(default = "default_ppqn")]
+    ppqn: u32,
+    #[serde(default = "default_pulse_ms")]
+    pulse_ms: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawVoices {
+    #[serde(default = "default_voice_address")]
+    address: String,
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    unison: bool,
+    groups: Vec<RawVoiceGroup>,
+    #[serde(default)]
+    pitch_scale: PitchScale,
+    #[serde(default = "default_root_note")]
+    root_note: f32,
+    #[serde(default)]
+    glide_ms: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawVoiceGroup {
+    pitch: usize,
+    gate: usize,
+    velocity: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+/// Fields that are checked after parsing keep their span, so errors point at
+/// the offending key rather than the `[[mapping]]` header.
+struct RawMapping {
+    address: Spanned<String>,
+    #[serde(default)]
+    arg: usize,
+    cv_channel: Option<Spanned<usize>>,
+    /// Shorthand for `midi = "cc"` with `midi_number`.
+    midi_cc: Option<Spanned<u8>>,
+    midi: Option<Spanned<MidiType>>,
+    midi_number: Option<Spanned<u16>>,
+    midi_channel: Option<Spanned<u8>>,
+    midi_port: Option<Spanned<OneOrMany>>,
+    /// Most MIDI messages per second for this mapping.
+    midi_max_rate: Option<Spanned<f32>>,
+    #[serde(default = "default_midi_dedup")]
+    midi_dedup: bool,
+    osc_address: Option<Spanned<String>>,
+    osc_range: Option<Spanned<[f32; 2]>>,
+    #[serde(default)]
+    input: InputKind,
+    #[serde(alias = "input_range")]
+    range: Option<Spanned<[f32; 2]>>,
+    #[serde(default)]
+    curve: Curve,
+    cv_range: Option<Spanned<[f32; 2]>>,
+    #[serde(default)]
+    slew: SlewMode,
+    slew_ms: Option<Spanned<f32>>,
+    rise_ms: Option<Spanned<f32>>,
+    fall_ms: Option<Spanned<f32>>,
+    mode: Option<Spanned<CvMode>>,
+    trigger_ms: Option<Spanned<f32>>,
+    #[serde(default)]
+    pitch_input: PitchInput,
+    #[serde(default)]
+    pitch_scale: PitchScale,
+    root_note: Option<Spanned<f32>>,
+}
+
+/// Splits an optional field into its value and the offset its errors point
+/// at: the key's own, or the section's when it was left out.
+fn field<T>(field: Option<Spanned<T>>, section: usize) -> (Option<T>, usize) {
+    match field {
+        Some(field) => {
+            let at = field.span().start;
+            (Some(field.into_inner()), at)
+        }
+        None => (None, section),
+    }
+}
+
+/// Like `field`, with the default filled in.
+fn field_or<T>(value: Option<Spanned<T>>, default: T, section: usize) -> (T, usize) {
+    let (value, at) = field(value, section);
+    (value.unwrap_or(default), at)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn default_midi_channel() -> u8 {
+    1
+}
+
+fn default_midi_dedup() -> bool {
+    true
+}
+
+fn default_range() -> [f32; 2] {
+    [0.0, 1.0]
+}
+
+fn default_cv_range() -> [f32; 2] {
+    [-1.0, 1.0]
+}
+
+fn default_trigger_ms() -> f32 {
+    10.0
+}
+
+fn default_root_note() -> f32 {
+    60.0
+}
+
+fn default_ppqn() -> u32 {
+    4
+}
+
+fn default_pulse_ms() -> f32 {
+    5.0
+}
+
+fn default_lfo_rate() -> f32 {
+    1.0
+}
+
+fn default_depth() -> f32 {
+    1.0
+}
+
+fn default_generator_midi_rate() -> f32 {
+    50.0
+}
+
+fn default_attack_ms() -> f32 {
+    10.0
+}
+
+fn default_decay_ms() -> f32 {
+    100.0
+}
+
+fn default_sustain() -> f32 {
+    0.7
+}
+
+fn default_release_ms() -> f32 {
+    200.0
+}
+
+fn default_voice_address() -> String {
+    "/note".to_string()
+}
+
+/// The mappings used when no config file is given. Addresses beyond the
+/// available CV channels only send MIDI.
+pub fn default_mappings(cv_channels: usize) -> Vec<Mapping> {
+    [
+        ("/lfo1", 0),
+        ("/lfo2", 1),
+        ("/lfo3", 2),
+        ("/lfo4", 3),
+        ("/stepped32", 4),
+        ("/stepped8", 5),
+    ]
+    .iter()
+    .map(|&(address, channel)| Mapping {
+        address: address.to_string(),
+        arg: 0,
+        cv_channel: Some(channel).filter(|&c| c < cv_channels),
+        midi: Some(MidiTarget {
+            channel: 0,
+            kind: MidiKind::ControlChange(channel as u8),
+        }),
+        midi_ports: vec![0],
+        midi_limit: RateLimit {
+            min_interval: None,
+            dedup: true,
+        },
+        osc_address: None,
+        osc_range: (0.0, 1.0),
+        input: InputKind::Auto,
+        range: (0.0, 1.0),
+        curve: Curve::Linear,
+        cv_range: (-1.0, 1.0),
+        slew: Slew::default(),
+        mode: CvMode::Value,
+        trigger_ms: default_trigger_ms(),
+        pitch_input: PitchInput::Note,
+        pitch_scale: PitchScale::VoltPerOctave,
+        root_note: default_root_note(),
+    })
+    .collect()
+}
+
+/// Loads and validates a config against the available CV channels and MIDI ports.
+pub fn load(path: &Path, outputs: &Outputs) -> Result<Config, ConfigError> {
+    let source = fs::read_to_string(path).map_err(|e| ConfigError {
+        path: path.to_path_buf(),
+        line: None,
+        message: e.to_string(),
+    })?;
+    parse(&source, outputs).map_err(|(offset, message)| ConfigError {
+        path: path.to_path_buf(),
+        line: offset.map(|o| line_of(&source, o)),
+        message,
+    })
+}
+
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+/// Parses a config, returning the byte offset of the offending item on error.
+fn parse(source: &str, outputs: &Outputs) -> Result<Config, (Option<usize>, String)> {
+    let raw: RawConfig =
+        toml::from_str(source).map_err(|e| (e.span().map(|s| s.start), e.message().to_string()))?;
+    let starts = Starts {
+        mappings: raw.mapping.iter().map(|m| m.span().start).collect(),
+        voices: raw.voices.as_ref().map(|v| v.span().start),
+        clock: raw.clock.as_ref().map(|c| c.span().start),
+        lfos: raw.lfo.iter().map(|l| l.span().start).collect(),
+        envelopes: raw.envelope.iter().map(|e| e.span().start).collect(),
+    };
+
+    let voices = raw
+        .voices
+        .map(|spanned| {
+            let start = spanned.span().start;
+            parse_voices(spanned.into_inner(), outputs).map_err(|message| (Some(start), message))
+        })
+        .transpose()?;
+    let clock = raw
+        .clock
+        .map(|spanned| {
+            let start = spanned.span().start;
+            parse_clock(spanned.into_inner(), outputs).map_err(|message| (Some(start), message))
+        })
+        .transpose()?;
+    let lfos = raw
+        .lfo
+        .into_iter()
+        .enumerate()
+        .map(|(index, spanned)| {
+            let start = spanned.span().start;
+            parse_lfo(spanned.into_inner(), index, outputs)
+                .map_err(|message| (Some(start), message))
+        })
+        .collect::<Result<_, _>>()?;
+    let envelopes = raw
+        .envelope
+        .into_iter()
+        .map(|spanned| {
+            let start = spanned.span().start;
+            parse_envelope(spanned.into_inner(), outputs).map_err(|message| (Some(start), message))
+        })
+        .collect::<Result<_, _>>()?;
+    let mappings = raw
+        .mapping
+        .into_iter()
+        .map(|spanned| {
+            let start = spanned.span().start;
+            let m = spanned.into_inner();
+            let fail = |at: usize| move |message: String| (Some(at), message);
+
+            let address_at = m.address.span().start;
+            let address = m.address.into_inner();
+            let (cv_channel, cv_channel_at) = field(m.cv_channel, start);
+            let (midi_cc, midi_cc_at) = field(m.midi_cc, start);
+            let (midi_type, midi_type_at) = field(m.midi, start);
+            let (midi_number, midi_number_at) = field(m.midi_number, start);
+            let (midi_channel, midi_channel_at) =
+                field_or(m.midi_channel, default_midi_channel(), start);
+            let (midi_port, midi_port_at) = field(m.midi_port, start);
+            let (midi_max_rate, midi_max_rate_at) = field(m.midi_max_rate, start);
+            let (osc_address, osc_address_at) = field(m.osc_address, start);
+            let (osc_range, osc_range_at) = field_or(m.osc_range, default_range(), start);
+            let (range, range_at) = field_or(m.range, default_range(), start);
+            let (cv_range, cv_range_at) = field_or(m.cv_range, default_cv_range(), start);
+            let (slew_ms, slew_ms_at) = field(m.slew_ms, start);
+            let (rise_ms, rise_ms_at) = field(m.rise_ms, start);
+            let (fall_ms, fall_ms_at) = field(m.fall_ms, start);
+            let (mode, mode_at) = field_or(m.mode, CvMode::default(), start);
+            let (trigger_ms, trigger_ms_at) = field_or(m.trigger_ms, default_trigger_ms(), start);
+            let (root_note, root_note_at) = field_or(m.root_note, default_root_note(), start);
+
+            if !address.starts_with('/') {
+                return Err(fail(address_at)(format!(
+                    "address `{}` must start with `/`",
+                    address
+                )));
+            }
+            if let Err(e) = pattern::validate(&address) {
+                return Err(fail(address_at)(format!(
+                    "invalid address pattern `{}`: {}",
+                    address, e
+                )));
+            }
+            let midi_kind = match (midi_cc, midi_type) {
+                (Some(_), Some(_)) => {
+                    return Err(fail(midi_type_at)(
+                        "use either `midi_cc` or `midi`, not both".to_string(),
+                    ));
+                }
+                (Some(cc), None) => {
+                    Some(MidiKind::new(MidiType::Cc, Some(cc as u16)).map_err(fail(midi_cc_at)))
+                }
+                (None, Some(midi_type)) => {
+                    let at = match midi_number {
+                        Some(_) => midi_number_at,
+                        None => midi_type_at,
+                    };
+                    Some(MidiKind::new(midi_type, midi_number).map_err(fail(at)))
+                }
+                (None, None) => None,
+            }
+            .transpose()?;
+            let midi_interval = midi_max_rate
+                .map(midi_interval)
+                .transpose()
+                .map_err(fail(midi_max_rate_at))?;
+            if cv_channel.is_none() && midi_kind.is_none() && osc_address.is_none() {
+                return Err(fail(start)(format!(
+                    "mapping for `{}` needs a `cv_channel`, a `midi` message or an `osc_address`",
+                    address
+                )));
+            }
+            if let Some(osc_address) = &osc_address {
+                if !osc_address.starts_with('/')
+                    || osc_address.contains(['*', '?', '[', ']', '{', '}'])
+                {
+                    return Err(fail(osc_address_at)(format!(
+                        "`osc_address` `{}` must be a plain address starting with `/`",
+                        osc_address
+                    )));
+                }
+                if !outputs.osc_out {
+                    return Err(fail(osc_address_at)(
+                        "`osc_address` needs --osc-target".to_string(),
+                    ));
+                }
+            }
+            let [osc_low, osc_high] = osc_range;
+            if !osc_low.is_finite() || !osc_high.is_finite() {
+                return Err(fail(osc_range_at)(format!(
+                    "invalid `osc_range` [{}, {}]",
+                    osc_low, osc_high
+                )));
+            }
+            if let Some(channel) = cv_channel
+                && !(1..=outputs.cv_channels).contains(&channel)
+            {
+                return Err(fail(cv_channel_at)(format!(
+                    "`cv_channel` must be 1-{}, got {}",
+                    outputs.cv_channels, channel
+                )));
+            }
+            let midi_ports = port_indices(outputs, midi_port).map_err(fail(midi_port_at))?;
+            if !(1..=16).contains(&midi_channel) {
+                return Err(fail(midi_channel_at)(format!(
+                    "`midi_channel` must be 1-16, got {}",
+                    midi_channel
+                )));
+            }
+            let [min, max] = range;
+            if !min.is_finite() || !max.is_finite() || min == max {
+                return Err(fail(range_at)(format!(
+                    "invalid `range` [{}, {}]",
+                    min, max
+                )));
+            }
+            let [low, high] = cv_range;
+            if !(-1.0..=1.0).contains(&low) || !(-1.0..=1.0).contains(&high) {
+                return Err(fail(cv_range_at)(format!(
+                    "`cv_range` [{}, {}] must lie within -1..1",
+                    low, high
+                )));
+            }
+            for (ms, at) in [
+                (slew_ms, slew_ms_at),
+                (rise_ms, rise_ms_at),
+                (fall_ms, fall_ms_at),
+            ] {
+                if ms.is_some_and(|ms| !(ms.is_finite() && ms >= 0.0)) {
+                    return Err(fail(at)("slew times must be zero or positive".to_string()));
+                }
+            }
+            let slew = Slew {
+                mode: m.slew,
+                rise_ms: rise_ms.or(slew_ms).unwrap_or(0.0),
+                fall_ms: fall_ms.or(slew_ms).unwrap_or(0.0),
+            };
+            if mode == CvMode::Pitch && cv_channel.is_none() {
+                return Err(fail(mode_at)(
+                    "`pitch` mode needs a `cv_channel`".to_string(),
+                ));
+            }
+            if !(trigger_ms.is_finite() && trigger_ms > 0.0) {
+                return Err(fail(trigger_ms_at)(
+                    "`trigger_ms` must be positive".to_string(),
+                ));
+            }
+            if !root_note.is_finite() {
+                return Err(fail(root_note_at)(
+                    "`root_note` must be a number".to_string(),
+                ));
+            }
+
+            Ok(Mapping {
+                address,
+                arg: m.arg,
+                cv_channel: cv_channel.map(|c| c - 1),
+                midi: midi_kind.map(|kind| MidiTarget {
+                    channel: midi_channel - 1,
+                    kind,
+                }),
+                midi_ports,
+                midi_limit: RateLimit {
+                    min_interval: midi_interval,
+                    dedup: m.midi_dedup,
+                },
+                osc_address,
+                osc_range: (osc_low, osc_high),
+                input: m.input,
+                range: (min, max),
+                curve: m.curve,
+                cv_range: (low, high),
+                slew,
+                mode,
+                trigger_ms,
+                pitch_input: m.pitch_input,
+                pitch_scale: m.pitch_scale,
+                root_note,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    let config = Config {
+        mappings,
+        voices,
+        clock,
+        lfos,
+        envelopes,
+    };
+    check_generator_channels(&config, &starts, source)?;
+    Ok(config)
+}
+
+/// Where each section starts in the source.
+struct Starts {
+    mappings: Vec<usize>,
+    voices: Option<usize>,
+    clock: Option<usize>,
+    lfos: Vec<usize>,
+    envelopes: Vec<usize>,
+}
+
+/// LFOs and envelopes drive their CV channel on every frame, so whatever
+/// else was sent there would be overwritten. Rejects a generator sharing its
+/// channel with any other section.
+fn check_generator_channels(
+    config: &Config,
+    starts: &Starts,
+    source: &str,
+) -> Result<(), (Option<usize>, String)> {
+    let mut owners: Vec<(usize, &str, usize)> = Vec::new();
+    for (mapping, &start) in config.mappings.iter().zip(&starts.mappings) {
+        owners.extend(mapping.cv_channel.map(|c| (c, "[[mapping]]", start)));
+    }
+    if let (Some(voices), Some(start)) = (&config.voices, starts.voices) {
+        for group in &voices.groups {
+            for channel in [Some(group.pitch), Some(group.gate), group.velocity] {
+                owners.extend(channel.map(|c| (c, "[voices]", start)));
+            }
+        }
+    }
+    if let (Some(clock), Some(start)) = (&config.clock, starts.clock) {
+        owners.extend(clock.cv_channel.map(|c| (c, "[clock]", start)));
+    }
+
+    let lfos = config
+        .lfos
+        .iter()
+        .map(|l| (&l.output, "[[lfo]]"))
+        .zip(&starts.lfos);
+    let envelopes = config
+        .envelopes
+        .iter()
+        .map(|e| (&e.output, "[[envelope]]"))
+        .zip(&starts.envelopes);
+    for ((output, section), &start) in lfos.chain(envelopes) {
+        let Some(channel) = output.cv_channel else {
+            continue;
+        };
+        if let Some((_, other, other_start)) = owners.iter().find(|(c, _, _)| *c == channel) {
+            return Err((
+                Some(start),
+                format!(
+                    "`cv_channel` {} is already used by the {} on line {}",
+                    channel + 1,
+                    other,
+                    line_of(source, *other_start)
+                ),
+            ));
+        }
+        owners.push((channel, section, start));
+    }
+    Ok(())
+}
+
+fn parse_lfo(l: RawLfo, index: usize, outputs: &Outputs) -> Result<LfoConfig, String> {
+    if !(l.rate.is_finite() && l.rate >= 0.0) {
+        return Err("LFO `rate` must be zero or positive".to_string());
+    }
+    if ![l.depth, l.offset, l.phase].iter().all(|v| v.is_finite()) {
+        return Err("LFO `depth`, `offset` and `phase` must be numbers".to_string());
+    }
+    Ok(LfoConfig {
+        lfo: Lfo {
+            shape: l.shape,
+            depth: l.depth,
+            offset: l.offset,
+            seed: index as u64,
+        },
+        rate: l.rate,
+        synced: l.sync,
+        phase: l.phase,
+        output: generator_output(
+            l.cv_channel,
+            l.midi_cc,
+            l.midi_channel,
+            l.midi_port,
+            l.midi_max_rate,
+            outputs,
+        )?,
+    })
+}
+
+fn parse_envelope(e: RawEnvelope, outputs: &Outputs) -> Result<EnvelopeConfig, String> {
+    let valid_ms = |ms: f32| ms.is_finite() && ms >= 0.0;
+    if ![e.attack_ms, e.decay_ms, e.release_ms]
+        .into_iter()
+        .all(valid_ms)
+    {
+        return Err("envelope times must be zero or positive".to_string());
+    }
+    if !(0.0..=1.0).contains(&e.sustain) {
+        return Err(format!("`sustain` must be 0-1, got {}", e.sustain));
+    }
+    if !e.depth.is_finite() {
+        return Err("envelope `depth` must be a number".to_string());
+    }
+    Ok(EnvelopeConfig {
+        adsr: Adsr {
+            attack_ms: e.attack_ms,
+            decay_ms: e.decay_ms,
+            sustain: e.sustain,
+            release_ms: e.release_ms,
+            depth: e.depth,
+        },
+        output: generator_output(
+            e.cv_channel,
+            e.midi_cc,
+            e.midi_channel,
+            e.midi_port,
+            e.midi_max_rate,
+            outputs,
+        )?,
+    })
+}
+
+/// Validates where an LFO or envelope goes.
+fn generator_output(
+    cv_channel: Option<usize>,
+    midi_cc: Option<u8>,
+    midi_channel: u8,
+    midi_port: Option<OneOrMany>,
+    midi_max_rate: f32,
+    outputs: &Outputs,
+) -> Result<GeneratorOutput, String> {
+    if cv_channel.is_none() && midi_cc.is_none() {
+        return Err("an LFO or envelope needs a `cv_channel` or a `midi_cc`".to_string());
+    }
+    if let Some(channel) = cv_channel
+        && !(1..=outputs.cv_channels).contains(&channel)
+    {
+        return Err(format!(
+            "`cv_channel` must be 1-{}, got {}",
+            outputs.cv_channels, channel
+        ));
+    }
+    let midi = midi_cc
+        .map(|cc| MidiKind::new(MidiType::Cc, Some(cc as u16)))
+        .transpose()?;
+    if !(1..=16).contains(&midi_channel) {
+        return Err(format!("`midi_channel` must be 1-16, got {}", midi_channel));
+    }
+    let midi_interval = midi_interval(midi_max_rate)?;
+    Ok(GeneratorOutput {
+        cv_channel: cv_channel.map(|c| c - 1),
+        midi: midi.map(|kind| MidiTarget {
+            channel: midi_channel - 1,
+            kind,
+        }),
+        midi_ports: port_indices(outputs, midi_port)?,
+        midi_interval,
+    })
+}
+
+fn parse_clock(c: RawClock, outputs: &Outputs) -> Result<ClockConfig, String> {
+    if let Some(channel) = c.cv_channel
+        && !(1..=outputs.cv_channels).contains(&channel)
+    {
+        return Err(format!(
+            "clock `cv_channel` must be 1-{}, got {}",
+            outputs.cv_channels, channel
+        ));
+    }
+    if !(1..=96).contains(&c.ppqn) {
+        return Err(format!("`ppqn` must be 1-96, got {}", c.ppqn));
+    }
+    if !(c.pulse_ms.is_finite() && c.pulse_ms > 0.0) {
+        return Err("`pulse_ms` must be positive".to_string());
+    }
+    Ok(ClockConfig {
+        sync: c.sync,
+        midi_ports: port_indices(outputs, c.midi_port)?,
+        cv_channel: c.cv_channel.map(|c| c - 1),
+        ppqn: c.ppqn,
+        pulse_ms: c.pulse_ms,
+    })
+}
+
+fn parse_voices(v: RawVoices, outputs: &Outputs) -> Result<VoiceConfig, String> {
+    if !v.address.starts_with('/') {
+        return Err(format!("voice address `{}` must start with `/`", v.address));
+    }
+    if let Err(e) = pattern::validate(&v.address) {
+        return Err(format!(
+            "invalid voice address pattern `{}`: {}",
+            v.address, e
+        ));
+    }
+    if v.groups.is_empty() {
+        return Err("`voices` needs at least one group".to_string());
+    }
+    if !(v.glide_ms.is_finite() && v.glide_ms >= 0.0) {
+        return Err("`glide_ms` must be zero or positive".to_string());
+    }
+    if !v.root_note.is_finite() {
+        return Err("`root_note` must be a number".to_string());
+    }
+    let mut used = Vec::new();
+    let mut channel = |channel: usize| {
+        if !(1..=outputs.cv_channels).contains(&channel) {
+            return Err(format!(
+                "voice channels must be 1-{}, got {}",
+                outputs.cv_channels, channel
+            ));
+        }
+        if used.contains(&channel) {
+            return Err(format!("voice channel {} is used twice", channel));
+        }
+        used.push(channel);
+        Ok(channel - 1)
+    };
+    let groups = v
+        .groups
+        .iter()
+        .map(|g| {
+            Ok(VoiceGroup {
+                pitch: channel(g.pitch)?,
+                gate: channel(g.gate)?,
+                velocity: g.velocity.map(&mut channel).transpose()?,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(VoiceConfig {
+        address: v.address,
+        priority: v.priority,
+        unison: v.unison,
+        groups,
+        pitch_scale: v.pitch_scale,
+        root_note: v.root_note,
+        glide: Slew {
+            mode: SlewMode::Linear,
+            rise_ms: v.glide_ms,
+            fall_ms: v.glide_ms,
+        },
+    })
+}
+
+/// Turns a `midi_max_rate` into the interval between messages.
+fn midi_interval(rate: f32) -> Result<Duration, String> {
+    if !(MIN_MIDI_RATE..=MAX_MIDI_RATE).contains(&rate) {
+        return Err(format!(
+            "`midi_max_rate` must be {}-{}, got {}",
+            MIN_MIDI_RATE, MAX_MIDI_RATE, rate
+        ));
+    }
+    Ok(Duration::from_secs_f32(1.0 / rate))
+}
+
+/// Resolves `midi_port` names, defaulting to the first port.
+fn port_indices(outputs: &Outputs, ports: Option<OneOrMany>) -> Result<Vec<usize>, String> {
+    match ports {
+        None => Ok(vec![0]),
+        Some(OneOrMany::One(name)) => Ok(vec![port_index(outputs, &name)?]),
+        Some(OneOrMany::Many(names)) => {
+            names.iter().map(|name| port_index(outputs, name)).collect()
+        }
+    }
+}
+
+fn port_index(outputs: &Outputs, name: &str) -> Result<usize, String> {
+    outputs
+        .midi_ports
+        .iter()
+        .position(|port| port == name)
+        .ok_or_else(|| {
+            format!(
+                "unknown `midi_port` `{}`, open it with --midi-device or --virtual-midi \
+                 (open: {:?})",
+                name, outputs.midi_ports
+            )
+        })
+}
+
+/// Polls the config file and swaps in the new config whenever it changes.
+/// A config that fails to load is reported and the previous one stays active.
+pub fn watch(path: PathBuf, outputs: Outputs, config: SharedConfig) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    thread::spawn(move || {
+        let mut last_modified = modified(&path);
+        loop {
+            thread::sleep(Duration::from_millis(500));
+            let current = modified(&path);
+            if current.is_none() || current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            match load(&path, &outputs) {
+                Ok(new_config) => {
+                    println!(
+                        "Reloaded {} mappings from {}",
+                        new_config.mappings.len(),
+                        path.display()
+                    );
+                    *config.lock().unwrap() = Arc::new(new_config);
+                }
+                Err(e) => eprintln!("Config reload rejected, keeping previous mappings: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs() -> Outputs {
+        Outputs {
+            cv_channels: 8,
+            midi_ports: vec!["synth".to_string()],
+            osc_out: false,
+        }
+    }
+
+    /// The line and message of a config error.
+    fn error(source: &str) -> (usize, String) {
+        let (offset, message) = parse(source, &outputs()).unwrap_err();
+        (line_of(source, offset.unwrap_or(0)), message)
+    }
+
+    #[test]
+    fn parses_a_mapping() {
+        let source = "\
+[[mapping]]
+address = \"/fader/*\"
+cv_channel = 2
+midi_cc = 7
+midi_channel = 3
+range = [0.0, 127.0]
+slew_ms = 20.0
+";
+        let config = parse(source, &outputs()).unwrap();
+        let [mapping] = &config.mappings[..] else {
+            panic!("expected one mapping");
+        };
+        assert_eq!(mapping.address, "/fader/*");
+        assert_eq!(mapping.cv_channel, Some(1));
+        assert_eq!(
+            mapping.midi,
+            Some(MidiTarget {
+                channel: 2,
+                kind: MidiKind::ControlChange(7),
+            })
+        );
+        assert_eq!(mapping.midi_ports, [0]);
+        assert_eq!(mapping.range, (0.0, 127.0));
+        assert_eq!((mapping.slew.rise_ms, mapping.slew.fall_ms), (20.0, 20.0));
+        assert_eq!(mapping.mode, CvMode::Value);
+        assert!(config.voices.is_none() && config.clock.is_none());
+    }
+
+    #[test]
+    fn the_example_config_parses() {
+        let source = include_str!("../config.example.toml");
+        parse(source, &outputs()).unwrap();
+    }
+
+    #[test]
+    fn errors_point_at_their_line() {
+        for (source, line, message) in [
+            (
+                "[[mapping]]\naddress = \"/a\"\ncv_channel = 1\n\n[[mapping]]\naddress = \"/b\"\ncv_channel = 9\n",
+                7,
+                "`cv_channel` must be 1-8, got 9",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\n",
+                1,
+                "mapping for `/a` needs a `cv_channel`, a `midi` message or an `osc_address`",
+            ),
+            (
+                "[[mapping]]\naddress = \"a\"\ncv_channel = 1\n",
+                2,
+                "address `a` must start with `/`",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a/[b\"\ncv_channel = 1\n",
+                2,
+                "invalid address pattern `/a/[b`: unterminated `[`",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\nmidi_cc = 1\nmidi = \"note\"\nmidi_number = 60\n",
+                4,
+                "use either `midi_cc` or `midi`, not both",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\ncv_channel = 1\nrange = [1.0, 1.0]\n",
+                4,
+                "invalid `range` [1, 1]",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\ncv_channel = 1\nroot_note = nan\n",
+                4,
+                "`root_note` must be a number",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\nmidi_cc = 1\nmidi_port = \"nowhere\"\n",
+                4,
+                "unknown `midi_port` `nowhere`, open it with --midi-device or --virtual-midi (open: [\"synth\"])",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\nmidi = \"note\"\nmidi_number = 128\n",
+                4,
+                "`midi_number` for note must be 0-127, got 128",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\nmidi = \"nrpn\"\n",
+                3,
+                "nrpn needs a `midi_number`",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\nmidi_cc = 1\n\nmidi_channel = 17\n",
+                5,
+                "`midi_channel` must be 1-16, got 17",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\ncv_channel = 1\ncv_range = [0.0, 2.0]\n",
+                4,
+                "`cv_range` [0, 2] must lie within -1..1",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\ncv_channel = 1\nslew_ms = 5\nfall_ms = -1\n",
+                5,
+                "slew times must be zero or positive",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\nmidi_cc = 1\nmode = \"pitch\"\n",
+                4,
+                "`pitch` mode needs a `cv_channel`",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\ncv_channel = 1\ntrigger_ms = 0\n",
+                4,
+                "`trigger_ms` must be positive",
+            ),
+            (
+                "[[mapping]]\naddress = \"/a\"\ncv_channel = 1\nosc_address = \"/b\"\n",
+                4,
+                "`osc_address` needs --osc-target",
+            ),
+        ] {
+            assert_eq!(error(source), (line, message.to_string()), "{}", source);
+        }
+    }
+
+    #[test]
+    fn midi_rates_are_bounded() {
+        let source = |rate| {
+            format!(
+                "[[mapping]]\naddress = \"/a\"\nmidi_cc = 1\nmidi_max_rate = {}\n",
+                rate
+            )
+        };
+        assert!(parse(&source("0.01"), &outputs()).is_ok());
+        let lfo = |rate| format!("[[lfo]]\nmidi_cc = 1\nmidi_max_rate = {}\n", rate);
+        for rate in ["1e-20", "0.0", "-1.0", "nan", "inf", "1e9"] {
+            let (line, message) = error(&source(rate));
+            assert_eq!(line, 4);
+            assert!(
+                message.starts_with("`midi_max_rate` must be 0.01-10000"),
+                "{}",
+                message
+            );
+            let (_, message) = error(&lfo(rate));
+            assert!(
+                message.starts_with("`midi_max_rate` must be 0.01-10000"),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_fields_and_bad_toml_are_reported() {
+        let (line, message) = error("[[mapping]]\naddress = \"/a\"\ncv_chanel = 1\n");
+        assert_eq!(line, 3);
+        assert!(message.contains("unknown field `cv_chanel`"), "{}", message);
+
+        let (line, _) = error("[[mapping]]\naddress = \"/a\"\ncv_channel = \n");
+        assert_eq!(line, 3);
+
+        let (line, message) = error("[[mapping]]\naddress = \"/a\"\ncv_channel = \"one\"\n");
+        assert_eq!(line, 3);
+        assert!(message.contains("invalid type"), "{}", message);
+    }
+
+    #[test]
+    fn generators_need_a_channel_of_their_own() {
+        let source = "\
+[[mapping]]
+address = \"/a\"
+cv_channel = 1
+
+[[lfo]]
+cv_channel = 2
+
+[[envelope]]
+cv_channel = 1
+";
+        assert_eq!(
+            error(source),
+            (
+                8,
+                "`cv_channel` 1 is already used by the [[mapping]] on line 1".to_string()
+            )
+        );
+
+        let source = "\
+[clock]
+cv_channel = 3
+
+[[lfo]]
+cv_channel = 4
+
+[[lfo]]
+cv_channel = 4
+";
+        assert_eq!(
+            error(source),
+            (
+                7,
+                "`cv_channel` 4 is already used by the [[lfo]] on line 4".to_string()
+            )
+        );
+
+        let source = "\
+[[lfo]]
+cv_channel = 2
+midi_cc = 1
+
+[[envelope]]
+midi_cc = 1
+";
+        assert!(parse(source, &outputs()).is_ok());
+    }
+}
@@ -0,0 +1,87 @@
+use serde::Deserialize;
+
+/// Steepness of the exponential and logarithmic curves.
+const STEEPNESS: f32 = 4.0;
+
+/// Response curve applied to a normalized 0..1 value.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(try_from = "RawCurve")]
+pub enum Curve {
+    #[default]
+    Linear,
+    Exponential,
+    Logarithmic,
+    Sigmoid,
+    /// Evenly spaced points across 0..1, linearly interpolated.
+    Table(Vec<f32>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawCurve {
+    Named(String),
+    Table(Vec<f32>),
+}
+
+impl TryFrom<RawCurve> for Curve {
+    type Error = String;
+
+    fn try_from(raw: RawCurve) -> Result<Self, Self::Error> {
+        match raw {
+            RawCurve::Named(name) => match name.as_str() {
+                "linear" => Ok(Curve::Linear),
+                "exponential" => Ok(Curve::Exponential),
+                "logarithmic" => Ok(Curve::Logarithmic),
+                "s-curve" => Ok(Curve::Sigmoid),
+                _ => Err(format!(
+                    "unknown curve `{}`, expected `linear`, `exponential`, `logarithmic`, \
+                     `s-curve` or a table of values",
+                    name
+                )),
+            },
+            RawCurve::Table(points) if points.len() < 2 => {
+                Err("a curve table needs at least 2 points".to_string())
+            }
+            RawCurve::Table(points) => match points.iter().find(|p| !(0.0..=1.0).contains(*p)) {
+                Some(point) => Err(format!("curve table values must be 0-1, got {}", point)),
+                None => Ok(Curve::Table(points)),
+            },
+        }
+    }
+}
+
+impl Curve {
+    pub fn apply(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => x,
+            Curve::Exponential => ((STEEPNESS * x).exp() - 1.0) / (STEEPNESS.exp() - 1.0),
+            Curve::Logarithmic => (1.0 + (STEEPNESS.exp() - 1.0) * x).ln() / STEEPNESS,
+            Curve::Sigmoid => x * x * (3.0 - 2.0 * x),
+            Curve::Table(points) => {
+                let pos = x * (points.len() - 1) as f32;
+                let i = (pos as usize).min(points.len() - 2);
+                let frac = pos - i as f32;
+                points[i] + (points[i + 1] - points[i]) * frac
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tables_only_take_values_in_range() {
+        let table = |points: &[f32]| Curve::try_from(RawCurve::Table(points.to_vec()));
+        assert_eq!(
+            table(&[0.0, 0.5, 1.0]),
+            Ok(Curve::Table(vec![0.0, 0.5, 1.0]))
+        );
+        for bad in [f32::NAN, f32::INFINITY, -0.1, 1.5] {
+            assert!(table(&[0.0, bad]).is_err(), "{}", bad);
+        }
+        assert!(table(&[0.5]).is_err());
+    }
+}
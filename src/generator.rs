@@ -0,0 +1,750 @@
+//! Built-in LFOs and ADSR envelopes, controlled over OSC.
+//!
+//! The audio callback renders them on their CV channels. The MIDI side
+//! evaluates the same functions on its own to send CCs, so nothing flows back
+//! from the callback; random shapes are seeded per cycle so both agree.
+
+use crate::audio::{CvAction, CvEvent, CvTarget};
+use crate::clock::Clock;
+use crate::midi::MidiTarget;
+use rosc::{OscMessage, OscType};
+use serde::Deserialize;
+use std::f64::consts::PI;
+use std::time::{Duration, Instant};
+
+/// How often LFO phases are sent to the callback again, so its sample clock
+/// never drifts far from the tempo clock.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LfoShape {
+    #[default]
+    Sine,
+    Triangle,
+    /// Rising ramp.
+    Saw,
+    Square,
+    /// A new random value every cycle.
+    SampleHold,
+    /// Random values every cycle, smoothly joined.
+    SmoothRandom,
+}
+
+impl LfoShape {
+    const ALL: [LfoShape; 6] = [
+        LfoShape::Sine,
+        LfoShape::Triangle,
+        LfoShape::Saw,
+        LfoShape::Square,
+        LfoShape::SampleHold,
+        LfoShape::SmoothRandom,
+    ];
+
+    /// Looks a shape up by its config name.
+    fn named(name: &str) -> Option<Self> {
+        Some(match name {
+            "sine" => LfoShape::Sine,
+            "triangle" => LfoShape::Triangle,
+            "saw" => LfoShape::Saw,
+            "square" => LfoShape::Square,
+            "sample-hold" => LfoShape::SampleHold,
+            "smooth-random" => LfoShape::SmoothRandom,
+            _ => return None,
+        })
+    }
+
+    /// The bipolar -1..1 value `cycles` periods in.
+    fn value(self, cycles: f64, seed: u64) -> f64 {
+        let phase = cycles.rem_euclid(1.0);
+        let cycle = cycles.floor() as i64;
+        match self {
+            LfoShape::Sine => (2.0 * PI * phase).sin(),
+            LfoShape::Triangle if phase < 0.25 => 4.0 * phase,
+            LfoShape::Triangle if phase < 0.75 => 2.0 - 4.0 * phase,
+            LfoShape::Triangle => 4.0 * phase - 4.0,
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square if phase < 0.5 => 1.0,
+            LfoShape::Square => -1.0,
+            LfoShape::SampleHold => random(seed, cycle),
+            LfoShape::SmoothRandom => {
+                let (from, to) = (random(seed, cycle), random(seed, cycle + 1));
+                from + (to - from) * (1.0 - (PI * phase).cos()) / 2.0
+            }
+        }
+    }
+}
+
+/// A repeatable -1..1 value per seed and cycle (SplitMix64).
+fn random(seed: u64, cycle: i64) -> f64 {
+    let mut x = (cycle as u64)
+        .wrapping_add(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+        .wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^= x >> 31;
+    (x >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+}
+
+/// What an LFO outputs, in full-scale units.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lfo {
+    pub shape: LfoShape,
+    pub depth: f32,
+    pub offset: f32,
+    /// Keeps random shapes of different LFOs apart.
+    pub seed: u64,
+}
+
+impl Lfo {
+    pub fn output(&self, cycles: f64) -> f32 {
+        (self.shape.value(cycles, self.seed) as f32 * self.depth + self.offset).clamp(-1.0, 1.0)
+    }
+}
+
+/// An ADSR envelope. Segments run at fixed rates: the attack would take
+/// `attack_ms` from 0 to 1, the release `release_ms` from 1 to 0, so a gate
+/// change halfway through carries on from the current level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adsr {
+    pub attack_ms: f32,
+    pub decay_ms: f32,
+    pub sustain: f32,
+    pub release_ms: f32,
+    /// Peak output in full-scale units.
+    pub depth: f32,
+}
+
+impl Adsr {
+    /// The level `secs` after the gate opened at level `from`.
+    pub fn held(&self, from: f32, secs: f32) -> f32 {
+        let attack = self.attack_ms / 1000.0;
+        let rise = (1.0 - from) * attack;
+        if secs < rise {
+            return from + secs / attack;
+        }
+        let decay = self.decay_ms / 1000.0;
+        let fall = secs - rise;
+        if fall < decay {
+            1.0 - (1.0 - self.sustain) * fall / decay
+        } else {
+            self.sustain
+        }
+    }
+
+    /// The level `secs` after the gate closed at level `from`.
+    pub fn released(&self, from: f32, secs: f32) -> f32 {
+        let release = self.release_ms / 1000.0;
+        if release > 0.0 {
+            (from - secs / release).max(0.0)
+        } else {
+            0.0
+        }
+    }
+
+    pub fn level(&self, gate: bool, from: f32, secs: f32) -> f32 {
+        if gate {
+            self.held(from, secs)
+        } else {
+            self.released(from, secs)
+        }
+    }
+}
+
+/// Where a generator's output goes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratorOutput {
+    /// Zero-based CV channel.
+    pub cv_channel: Option<usize>,
+    pub midi: Option<MidiTarget>,
+    pub midi_ports: Vec<usize>,
+    /// How often the value is sampled for MIDI.
+    pub midi_interval: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LfoConfig {
+    pub lfo: Lfo,
+    /// Cycles per second, or per beat when synced.
+    pub rate: f32,
+    /// Follows the clock's beat instead of running freely.
+    pub synced: bool,
+    /// Phase offset in cycles.
+    pub phase: f32,
+    pub output: GeneratorOutput,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvelopeConfig {
+    pub adsr: Adsr,
+    pub output: GeneratorOutput,
+}
+
+struct LfoRunner {
+    config: LfoConfig,
+    /// Cycles at a point in time, for free-running LFOs.
+    anchor: (Instant, f64),
+    next_midi: Instant,
+}
+
+impl LfoRunner {
+    fn cycles_at(&self, clock: &Clock, time: Instant) -> f64 {
+        if self.config.synced {
+            clock.beat_at(time) * self.config.rate as f64 + self.config.phase as f64
+        } else {
+            let (at, cycles) = self.anchor;
+            let elapsed = if time >= at {
+                (time - at).as_secs_f64()
+            } else {
+                -(at - time).as_secs_f64()
+            };
+            cycles + elapsed * self.config.rate as f64
+        }
+    }
+
+    /// Sends the settings, the current rate and the phase at `time`.
+    fn event(&self, clock: &Clock, time: Instant) -> Option<CvEvent> {
+        let hz = match self.config.synced {
+            true => self.config.rate * clock.bpm / 60.0,
+            false => self.config.rate,
+        };
+        Some(CvEvent {
+            channel: self.config.output.cv_channel?,
+            action: CvAction::Lfo {
+                lfo: self.config.lfo,
+                hz,
+                cycles: self.cycles_at(clock, time),
+            },
+            time,
+        })
+    }
+}
+
+struct EnvelopeRunner {
+    config: EnvelopeConfig,
+    gate: bool,
+    /// Level and time of the last gate change.
+    from: f32,
+    since: Instant,
+    next_midi: Instant,
+}
+
+impl EnvelopeRunner {
+    fn level_at(&self, time: Instant) -> f32 {
+        let secs = time.saturating_duration_since(self.since).as_secs_f32();
+        self.config.adsr.level(self.gate, self.from, secs)
+    }
+
+    fn event(&self, gate: Option<bool>, time: Instant) -> Option<CvEvent> {
+        Some(CvEvent {
+            channel: self.config.output.cv_channel?,
+            action: CvAction::Envelope {
+                adsr: self.config.adsr,
+                gate,
+            },
+            time,
+        })
+    }
+}
+
+/// The running LFOs and envelopes, with the changes made over OSC.
+#[derive(Default)]
+pub struct Generators {
+    lfos: Vec<LfoRunner>,
+    envelopes: Vec<EnvelopeRunner>,
+    /// The config sections the generators were started from.
+    configured: (Vec<LfoConfig>, Vec<EnvelopeConfig>),
+    synced_at: Option<Instant>,
+}
+
+impl Generators {
+    /// Restarts the generators when their config sections changed, dropping
+    /// OSC changes. Channels of the old generators return to 0.
+    pub fn follow_config(
+        &mut self,
+        lfos: &[LfoConfig],
+        envelopes: &[EnvelopeConfig],
+        clock: &Clock,
+        time: Instant,
+        cv_updates: &mut Vec<CvEvent>,
+    ) {
+        if self.configured.0 == lfos && self.configured.1 == envelopes {
+            return;
+        }
+        let old_outputs = self
+            .lfos
+            .iter()
+            .map(|l| &l.config.output)
+            .chain(self.envelopes.iter().map(|e| &e.config.output));
+        for channel in old_outputs.filter_map(|output| output.cv_channel) {
+            cv_updates.push(CvEvent {
+                channel,
+                action: CvAction::Set(CvTarget::default()),
+                time,
+            });
+        }
+
+        self.configured = (lfos.to_vec(), envelopes.to_vec());
+        self.lfos = lfos
+            .iter()
+            .map(|config| LfoRunner {
+                config: config.clone(),
+                anchor: (time, config.phase as f64),
+                next_midi: time,
+            })
+            .collect();
+        self.envelopes = envelopes
+            .iter()
+            .map(|config| EnvelopeRunner {
+                config: config.clone(),
+                gate: false,
+                from: 0.0,
+                since: time,
+                next_midi: time,
+            })
+            .collect();
+        cv_updates.extend(self.lfos.iter().filter_map(|l| l.event(clock, time)));
+        cv_updates.extend(
+            self.envelopes
+                .iter()
+                .filter_map(|e| e.event(Some(false), time)),
+        );
+    }
+
+    /// Applies `/lfo/<n>/<rate|depth|offset|shape|phase>` and
+    /// `/env/<n>/<gate|attack|decay|sustain|release|depth>`, with `n` counted
+    /// from 1 in config order. Returns whether the message was one of them.
+    pub fn handle(
+        &mut self,
+        message: &OscMessage,
+        clock: &Clock,
+        time: Instant,
+        cv_updates: &mut Vec<CvEvent>,
+    ) -> Result<bool, String> {
+        let parts: Vec<&str> = message.addr.split('/').collect();
+        let ["", kind @ ("lfo" | "env"), index, param] = parts[..] else {
+            return Ok(false);
+        };
+        let index = index
+            .parse::<usize>()
+            .ok()
+            .filter(|&i| i >= 1)
+            .map(|i| i - 1);
+        let arg = message.args.first();
+        let value = match arg {
+            Some(OscType::Float(v)) => Some(*v),
+            Some(OscType::Double(v)) => Some(*v as f32),
+            Some(OscType::Int(v)) => Some(*v as f32),
+            Some(OscType::Long(v)) => Some(*v as f32),
+            Some(OscType::Bool(v)) => Some(*v as u8 as f32),
+            _ => None,
+        };
+        let number = || value.filter(|v| v.is_finite()).ok_or("expected a number");
+
+        if kind == "lfo" {
+            let Some(runner) = index.and_then(|i| self.lfos.get_mut(i)) else {
+                return Err("no such LFO".to_string());
+            };
+            // Free-running LFOs keep their phase through changes.
+            runner.anchor = (time, runner.cycles_at(clock, time));
+            let config = &mut runner.config;
+            match param {
+                "rate" => config.rate = number()?.max(0.0),
+                "depth" => config.lfo.depth = number()?,
+                "offset" => config.lfo.offset = number()?,
+                "shape" => {
+                    config.lfo.shape = match arg {
+                        Some(OscType::String(name)) => LfoShape::named(name)
+                            .ok_or_else(|| format!("unknown LFO shape `{}`", name))?,
+                        _ => *LfoShape::ALL
+                            .get(number()? as usize)
+                            .ok_or("shape index out of range")?,
+                    }
+                }
+                "phase" => {
+                    let phase = number()?;
+                    config.phase = phase;
+                    runner.anchor = (time, phase as f64);
+                }
+                _ => return Err(format!("unknown LFO parameter `{}`", param)),
+            }
+            cv_updates.extend(runner.event(clock, time));
+        } else {
+            let Some(runner) = index.and_then(|i| self.envelopes.get_mut(i)) else {
+                return Err("no such envelope".to_string());
+            };
+            let adsr = &mut runner.config.adsr;
+            let mut gate = None;
+            match param {
+                "gate" => gate = Some(number()? != 0.0),
+                "attack" => adsr.attack_ms = number()?.max(0.0),
+                "decay" => adsr.decay_ms = number()?.max(0.0),
+                "sustain" => adsr.sustain = number()?.clamp(0.0, 1.0),
+                "release" => adsr.release_ms = number()?.max(0.0),
+                "depth" => adsr.depth = number()?,
+                _ => return Err(format!("unknown envelope parameter `{}`", param)),
+            }
+            // Opening an open gate retriggers, closing a closed one does nothing.
+            if let Some(open) = gate
+                && (open || runner.gate)
+            {
+                runner.from = runner.level_at(time);
+                runner.since = time;
+                runner.gate = open;
+            }
+            cv_updates.extend(runner.event(gate, time));
+        }
+        Ok(true)
+    }
+
+    /// Sends the LFO phases along again after the clock moved, and every
+    /// `SYNC_INTERVAL` regardless.
+    pub fn sync(
+        &mut self,
+        clock: &Clock,
+        time: Instant,
+        force: bool,
+        cv_updates: &mut Vec<CvEvent>,
+    ) {
+        if !force
+            && self
+                .synced_at
+                .is_some_and(|at| time.saturating_duration_since(at) < SYNC_INTERVAL)
+        {
+            return;
+        }
+        self.synced_at = Some(time);
+        cv_updates.extend(self.lfos.iter().filter_map(|l| l.event(clock, time)));
+    }
+
+    /// The 0..1 values due for MIDI, with their target and ports.
+    pub fn midi_due(&mut self, clock: &Clock, now: Instant) -> Vec<(MidiTarget, Vec<usize>, f32)> {
+        let mut due = Vec::new();
+        for lfo in &mut self.lfos {
+            if let Some(midi) = lfo.config.output.midi
+                && lfo.next_midi <= now
+            {
+                lfo.next_midi = now + lfo.config.output.midi_interval;
+                let value = lfo.config.lfo.output(lfo.cycles_at(clock, now));
+                due.push((
+                    midi,
+                    lfo.config.output.midi_ports.clone(),
+                    (value + 1.0) / 2.0,
+                ));
+            }
+        }
+        for envelope in &mut self.envelopes {
+            if let Some(midi) = envelope.config.output.midi
+                && envelope.next_midi <= now
+            {
+                envelope.next_midi = now + envelope.config.output.midi_interval;
+                let value = envelope.level_at(now) * envelope.config.adsr.depth;
+                due.push((midi, envelope.config.output.midi_ports.clone(), value));
+            }
+        }
+        due
+    }
+
+    /// Time left until the next MIDI value or phase sync is due, `None` when
+    /// there is nothing to do.
+    pub fn time_until_next(&self, now: Instant) -> Option<Duration> {
+        let lfos = self
+            .lfos
+            .iter()
+            .filter(|l| l.config.output.midi.is_some())
+            .map(|l| l.next_midi);
+        let envelopes = self
+            .envelopes
+            .iter()
+            .filter(|e| e.config.output.midi.is_some())
+            .map(|e| e.next_midi);
+        let sync = self
+            .synced_at
+            .filter(|_| {
+                self.lfos
+                    .iter()
+                    .any(|l| l.config.output.cv_channel.is_some())
+            })
+            .map(|at| at + SYNC_INTERVAL);
+        lfos.chain(envelopes)
+            .chain(sync)
+            .min()
+            .map(|next| next.saturating_duration_since(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::MidiKind;
+
+    fn output(cv_channel: usize) -> GeneratorOutput {
+        GeneratorOutput {
+            cv_channel: Some(cv_channel),
+            midi: Some(MidiTarget {
+                channel: 0,
+                kind: MidiKind::ControlChange(1),
+            }),
+            midi_ports: vec![0],
+            midi_interval: Duration::from_millis(20),
+        }
+    }
+
+    fn lfo_config(shape: LfoShape) -> LfoConfig {
+        LfoConfig {
+            lfo: Lfo {
+                shape,
+                depth: 1.0,
+                offset: 0.0,
+                seed: 0,
+            },
+            rate: 1.0,
+            synced: false,
+            phase: 0.0,
+            output: output(0),
+        }
+    }
+
+    fn adsr(attack_ms: f32, decay_ms: f32) -> Adsr {
+        Adsr {
+            attack_ms,
+            decay_ms,
+            sustain: 0.5,
+            release_ms: 0.0,
+            depth: 1.0,
+        }
+    }
+
+    /// One LFO and one envelope, started at `time`.
+    fn generators(time: Instant) -> Generators {
+        let mut generators = Generators::default();
+        let envelope = EnvelopeConfig {
+            adsr: adsr(10.0, 10.0),
+            output: output(1),
+        };
+        generators.follow_config(
+            &[lfo_config(LfoShape::Sine)],
+            &[envelope],
+            &Clock::default(),
+            time,
+            &mut Vec::new(),
+        );
+        generators
+    }
+
+    fn send(
+        generators: &mut Generators,
+        addr: &str,
+        arg: OscType,
+        time: Instant,
+    ) -> Result<bool, String> {
+        let message = OscMessage {
+            addr: addr.to_string(),
+            args: vec![arg],
+        };
+        generators.handle(&message, &Clock::default(), time, &mut Vec::new())
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn shapes_at_their_turning_points() {
+        let value = |shape: LfoShape, cycles| shape.value(cycles, 0);
+        for (cycles, sine, triangle, saw, square) in [
+            (0.0, 0.0, 0.0, -1.0, 1.0),
+            (0.25, 1.0, 1.0, -0.5, 1.0),
+            (0.5, 0.0, 0.0, 0.0, -1.0),
+            (0.75, -1.0, -1.0, 0.5, -1.0),
+            (1.0, 0.0, 0.0, -1.0, 1.0),
+            (-0.25, -1.0, -1.0, 0.5, -1.0),
+        ] {
+            assert!(
+                close(value(LfoShape::Sine, cycles), sine),
+                "sine {}",
+                cycles
+            );
+            assert!(close(value(LfoShape::Triangle, cycles), triangle));
+            assert!(close(value(LfoShape::Saw, cycles), saw), "saw {}", cycles);
+            assert!(close(value(LfoShape::Square, cycles), square));
+        }
+    }
+
+    #[test]
+    fn random_shapes_repeat_for_the_same_seed_and_cycle() {
+        let hold = |cycles, seed| LfoShape::SampleHold.value(cycles, seed);
+        assert_eq!(hold(3.1, 7), hold(3.9, 7));
+        assert_ne!(hold(3.9, 7), hold(4.1, 7));
+        assert_ne!(hold(3.5, 7), hold(3.5, 8));
+
+        // The callback and the MIDI side both evaluate the same function.
+        let lfo = Lfo {
+            shape: LfoShape::SmoothRandom,
+            depth: 1.0,
+            offset: 0.0,
+            seed: 3,
+        };
+        assert_eq!(lfo.output(12.345), lfo.output(12.345));
+
+        for cycle in -5..5 {
+            let cycles = cycle as f64;
+            let smooth = |cycles| LfoShape::SmoothRandom.value(cycles, 7);
+            // Smooth random passes through the S&H value at each cycle start
+            // and joins the cycles without a step.
+            assert!(close(smooth(cycles), hold(cycles, 7)));
+            assert!((smooth(cycles - 1e-6) - smooth(cycles)).abs() < 1e-4);
+            assert!((-1.0..=1.0).contains(&hold(cycles + 0.5, 7)));
+        }
+    }
+
+    #[test]
+    fn envelope_segments_without_attack_or_decay() {
+        let held = |attack_ms, decay_ms, secs| adsr(attack_ms, decay_ms).held(0.0, secs);
+        assert_eq!(held(0.0, 10.0, 0.0), 1.0);
+        assert_eq!(held(0.0, 10.0, 0.005), 0.75);
+        assert_eq!(held(10.0, 0.0, 0.005), 0.5);
+        assert_eq!(held(10.0, 0.0, 0.01), 0.5);
+        assert_eq!(held(0.0, 0.0, 0.0), 0.5);
+
+        // An attack from halfway up takes half as long.
+        assert_eq!(adsr(10.0, 10.0).held(0.5, 0.005), 1.0);
+
+        assert_eq!(adsr(0.0, 0.0).released(0.5, 0.0), 0.0);
+        let mut slow = adsr(0.0, 0.0);
+        slow.release_ms = 100.0;
+        assert_eq!(slow.released(0.5, 0.025), 0.25);
+        assert_eq!(slow.released(0.5, 1.0), 0.0);
+    }
+
+    #[test]
+    fn messages_address_generators_from_one() {
+        let time = Instant::now();
+        let mut generators = generators(time);
+        let rate = OscType::Float(2.0);
+
+        assert_eq!(
+            send(&mut generators, "/lfo/1/rate", rate.clone(), time),
+            Ok(true)
+        );
+        assert!(send(&mut generators, "/lfo/0/rate", rate.clone(), time).is_err());
+        assert!(send(&mut generators, "/lfo/2/rate", rate.clone(), time).is_err());
+        assert!(send(&mut generators, "/lfo/x/rate", rate.clone(), time).is_err());
+        assert!(send(&mut generators, "/lfo/1/speed", rate.clone(), time).is_err());
+        assert_eq!(
+            send(&mut generators, "/lfo1", rate.clone(), time),
+            Ok(false)
+        );
+        assert_eq!(
+            send(&mut generators, "/lfo/1/rate/x", rate.clone(), time),
+            Ok(false)
+        );
+        assert_eq!(
+            send(&mut generators, "/env/1/sustain", OscType::Int(1), time),
+            Ok(true)
+        );
+        assert!(
+            send(
+                &mut generators,
+                "/env/1/gate",
+                OscType::String("on".into()),
+                time
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn shapes_by_name_or_index() {
+        let time = Instant::now();
+        let mut generators = generators(time);
+        let mut shape = |arg| {
+            send(&mut generators, "/lfo/1/shape", arg, time)?;
+            Ok::<_, String>(generators.lfos[0].config.lfo.shape)
+        };
+
+        assert_eq!(shape(OscType::String("saw".into())), Ok(LfoShape::Saw));
+        assert_eq!(shape(OscType::Int(3)), Ok(LfoShape::Square));
+        assert_eq!(shape(OscType::Float(5.0)), Ok(LfoShape::SmoothRandom));
+        assert!(shape(OscType::Int(6)).is_err());
+        assert!(shape(OscType::String("noise".into())).is_err());
+    }
+
+    #[test]
+    fn settings_are_clamped() {
+        let time = Instant::now();
+        let mut generators = generators(time);
+        let mut send = |addr, value| send(&mut generators, addr, OscType::Float(value), time);
+        send("/lfo/1/rate", -1.0).unwrap();
+        send("/env/1/attack", -5.0).unwrap();
+        send("/env/1/sustain", 2.0).unwrap();
+        send("/env/1/release", f32::NEG_INFINITY).unwrap_err();
+
+        assert_eq!(generators.lfos[0].config.rate, 0.0);
+        let adsr = generators.envelopes[0].config.adsr;
+        assert_eq!(
+            (adsr.attack_ms, adsr.sustain, adsr.release_ms),
+            (0.0, 1.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn gates_retrigger_but_closing_twice_does_nothing() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut generators = generators(start);
+        let gate = |generators: &mut Generators, open: i32, time| {
+            send(generators, "/env/1/gate", OscType::Int(open), time).unwrap();
+            let envelope = &generators.envelopes[0];
+            (envelope.gate, envelope.from, envelope.since)
+        };
+
+        assert_eq!(gate(&mut generators, 0, ms(1)), (false, 0.0, start));
+        assert_eq!(gate(&mut generators, 1, ms(2)), (true, 0.0, ms(2)));
+        // Retriggering mid-attack carries on from the level reached.
+        assert_eq!(gate(&mut generators, 1, ms(7)), (true, 0.5, ms(7)));
+        assert_eq!(gate(&mut generators, 0, ms(30)), (false, 0.5, ms(30)));
+        assert_eq!(gate(&mut generators, 0, ms(40)), (false, 0.5, ms(30)));
+    }
+
+    #[test]
+    fn rate_changes_keep_the_phase() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut generators = generators(start);
+        let clock = Clock::default();
+
+        send(&mut generators, "/lfo/1/rate", OscType::Float(2.0), ms(250)).unwrap();
+        assert!(close(generators.lfos[0].cycles_at(&clock, ms(250)), 0.25));
+        assert!(close(generators.lfos[0].cycles_at(&clock, ms(500)), 0.75));
+
+        send(
+            &mut generators,
+            "/lfo/1/phase",
+            OscType::Float(0.5),
+            ms(500),
+        )
+        .unwrap();
+        assert!(close(generators.lfos[0].cycles_at(&clock, ms(500)), 0.5));
+    }
+
+    #[test]
+    fn midi_values_are_paced() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut generators = generators(start);
+        let clock = Clock::default();
+
+        // A sine starts at its midpoint, the closed envelope at 0.
+        let due = generators.midi_due(&clock, start);
+        let values: Vec<f32> = due.iter().map(|&(_, _, value)| value).collect();
+        assert_eq!(values, [0.5, 0.0]);
+        assert_eq!(
+            generators.time_until_next(ms(5)),
+            Some(Duration::from_millis(15))
+        );
+
+        assert!(generators.midi_due(&clock, ms(10)).is_empty());
+        assert_eq!(generators.midi_due(&clock, ms(20)).len(), 2);
+    }
+}
@@ -1,29 +1,130 @@
 #![allow(clippy::collapsible_match)]
-use clap::Parser;
+mod audio;
+mod bridge;
+mod calibrate;
+mod calibration;
+mod clock;
+mod config;
+mod curve;
+mod generator;
+mod midi;
+mod midi_in;
+mod osc;
+mod pattern;
+mod pitch;
+mod slew;
+mod sync;
+mod voices;
+
+use clap::{Parser, Subcommand};
+use cpal::Device;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, SampleRate, StreamConfig};
-use midir::{MidiOutput, MidiOutputConnection};
-use rosc::{OscMessage, OscPacket, decoder};
-use std::collections::HashMap;
-use std::net::UdpSocket;
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use rosc::OscPacket;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::process;
+use std::sync::mpsc::{self, Sender};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the MIDI counters are printed in debug mode.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The longest --latency-ms accepted.
+const MAX_LATENCY_MS: f32 = 10_000.0;
 
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(long, default_value = "8000")]
     osc_port: u16,
 
     #[arg(long)]
     audio_device: Option<String>,
 
+    /// MIDI output to open, may be repeated; mappings pick one by this name
+    /// with `midi_port`, and the first one is the default
+    #[arg(long)]
+    midi_device: Vec<String>,
+
+    /// Virtual MIDI output to create (ALSA/CoreMIDI), may be repeated; named
+    /// like --midi-device and opened after those. Without --midi-device no
+    /// hardware port is needed
+    #[arg(long)]
+    virtual_midi: Vec<String>,
+
+    /// MIDI input to read notes, CCs, pitch bend, aftertouch and clock from,
+    /// may be repeated; they arrive as `/midi/...` messages for the mappings
+    #[arg(long)]
+    midi_input: Vec<String>,
+
+    /// Host and port that `osc_address` mappings send to
     #[arg(long)]
-    midi_device: Option<String>,
+    osc_target: Option<SocketAddr>,
 
     #[arg(long, default_value = "false")]
     debug: bool,
 
     #[arg(long, default_value = "false")]
     list_devices: bool,
+
+    /// Number of CV output channels, defaults to the most the audio device supports
+    #[arg(long)]
+    channels: Option<usize>,
+
+    /// Sample rate in Hz, defaults to 48000 or the nearest the device supports
+    #[arg(long)]
+    sample_rate: Option<u32>,
+
+    /// Sample format such as f32, i32 or i16, defaults to the best the device supports
+    #[arg(long)]
+    sample_format: Option<String>,
+
+    /// Buffer size in frames, defaults to the device default
+    #[arg(long)]
+    buffer_size: Option<u32>,
+
+    /// Fixed delay in ms added to every CV event so it lands on the sample it was
+    /// sent for; use at least one buffer's length
+    #[arg(long, default_value = "0", value_parser = parse_latency)]
+    latency_ms: Duration,
+
+    /// TOML file with per-channel calibration for pitch CV
+    #[arg(long)]
+    calibration: Option<PathBuf>,
+
+    /// TOML file describing the OSC address mappings, reloaded when it changes
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Step CV channels through reference values and record measured voltages
+    Calibrate {
+        /// Calibration profile to write, existing channels are kept
+        #[arg(long, default_value = "calibration.toml")]
+        output: PathBuf,
+
+        /// 1-based channel to calibrate, may be repeated; defaults to all
+        #[arg(long)]
+        channel: Vec<usize>,
+
+        /// Number of reference values per channel
+        #[arg(long, default_value = "5")]
+        steps: usize,
+    },
+}
+
+fn parse_latency(arg: &str) -> Result<Duration, String> {
+    let ms: f32 = arg.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=MAX_LATENCY_MS).contains(&ms) {
+        return Err(format!("must be 0-{}", MAX_LATENCY_MS));
+    }
+    Ok(Duration::from_secs_f32(ms / 1000.0))
 }
 
 fn find_audio_device(name: &Option<String>) -> Device {
@@ -39,15 +140,45 @@ fn find_audio_device(name: &Option<String>) -> Device {
     }
 }
 
+fn max_output_channels(device: &Device) -> usize {
+    device
+        .supported_output_configs()
+        .map(|configs| configs.map(|c| c.channels() as usize).max().unwrap_or(0))
+        .unwrap_or(0)
+}
+
+fn output_channels(device: &Device, requested: Option<usize>) -> usize {
+    let supported = max_output_channels(device);
+    match requested {
+        Some(channels) if channels == 0 || channels > supported => {
+            eprintln!(
+                "Audio device supports up to {} channels, {} requested",
+                supported, channels
+            );
+            process::exit(1);
+        }
+        Some(channels) => channels,
+        None if supported == 0 => {
+            eprintln!("Audio device has no output channels");
+            process::exit(1);
+        }
+        None => supported,
+    }
+}
+
 fn list_audio_devices() {
     let host = cpal::default_host();
     println!("\n** Available Audio devices **");
     for device in host.output_devices().unwrap() {
-        println!("{}", device.name().unwrap(),);
+        println!(
+            "{} ({} channels)",
+            device.name().unwrap(),
+            max_output_channels(&device)
+        );
     }
 }
 
-fn find_midi_device(name: &Option<String>) -> MidiOutputConnection {
+fn find_midi_device(name: Option<&String>) -> MidiOutputConnection {
     let midi_out = MidiOutput::new("OSC-MIDI-Bridge").unwrap();
     let ports = midi_out.ports();
 
@@ -67,6 +198,53 @@ fn find_midi_device(name: &Option<String>) -> MidiOutputConnection {
         .expect("Failed to connect MIDI device")
 }
 
+#[cfg(unix)]
+fn create_virtual_midi(name: &str) -> MidiOutputConnection {
+    use midir::os::unix::VirtualOutput;
+
+    let midi_out = MidiOutput::new("OSC-MIDI-Bridge").unwrap();
+    println!("Created virtual MIDI output: {}", name);
+    midi_out
+        .create_virtual(name)
+        .expect("Failed to create virtual MIDI output")
+}
+
+#[cfg(not(unix))]
+fn create_virtual_midi(_name: &str) -> MidiOutputConnection {
+    eprintln!("Virtual MIDI outputs are not supported on this platform");
+    process::exit(1);
+}
+
+/// Opens a MIDI input and forwards its messages as OSC packets.
+fn open_midi_input(
+    name: &str,
+    packets: Sender<(SystemTime, OscPacket)>,
+) -> MidiInputConnection<()> {
+    let mut midi_in = MidiInput::new("OSC-MIDI-Bridge").unwrap();
+    midi_in.ignore(Ignore::SysexAndActiveSense);
+    let ports = midi_in.ports();
+    let port = ports
+        .iter()
+        .find(|p| midi_in.port_name(p).unwrap().contains(name))
+        .expect("MIDI input not found");
+
+    println!("Using MIDI input: {}", midi_in.port_name(port).unwrap());
+
+    midi_in
+        .connect(
+            port,
+            "osc-midi-in",
+            move |_, bytes, _| {
+                let received = SystemTime::now();
+                for message in midi_in::to_osc(bytes) {
+                    let _ = packets.send((received, OscPacket::Message(message)));
+                }
+            },
+            (),
+        )
+        .expect("Failed to connect MIDI input")
+}
+
 fn list_midi_devices() {
     let midi_out = MidiOutput::new("OSC-MIDI-Bridge").unwrap();
     let ports = midi_out.ports();
@@ -75,6 +253,12 @@ fn list_midi_devices() {
     for port in ports.iter() {
         println!("{}", midi_out.port_name(port).unwrap());
     }
+
+    let midi_in = MidiInput::new("OSC-MIDI-Bridge").unwrap();
+    println!("\n** Available MIDI inputs **");
+    for port in midi_in.ports().iter() {
+        println!("{}", midi_in.port_name(port).unwrap());
+    }
 }
 
 fn main() {
@@ -87,84 +271,170 @@ fn main() {
     }
 
     let audio_device = find_audio_device(&cmdline_args.audio_device);
-    println!("Using audio device: {}", audio_device.name().unwrap());
-    let midi_conn = Arc::new(Mutex::new(find_midi_device(&cmdline_args.midi_device)));
+    let channels = output_channels(&audio_device, cmdline_args.channels);
+    println!(
+        "Using audio device: {} ({} channels)",
+        audio_device.name().unwrap(),
+        channels
+    );
 
-    let channels = 8;
-    let latest_values = Arc::new(Mutex::new(vec![0f32; channels]));
+    let (cv_events, cv_consumer) = audio::cv_events();
 
-    let config = StreamConfig {
-        channels: channels as u16,
-        sample_rate: SampleRate(48000),
-        buffer_size: cpal::BufferSize::Default,
+    let request = audio::Request {
+        sample_rate: cmdline_args.sample_rate,
+        sample_format: cmdline_args.sample_format.clone(),
+        buffer_size: cmdline_args.buffer_size,
     };
+    let (config, format) =
+        audio::negotiate(&audio_device, channels, &request).unwrap_or_else(|e| {
+            eprintln!("Audio device: {}", e);
+            process::exit(1);
+        });
+    println!(
+        "Audio stream: {} Hz, {}, {} channels, buffer {:?}",
+        config.sample_rate.0, format, config.channels, config.buffer_size
+    );
 
-    let values_clone = latest_values.clone();
-
-    let stream = audio_device
-        .build_output_stream(
-            &config,
-            move |data: &mut [f32], _| {
-                let values = values_clone.lock().unwrap();
-                for frame in data.chunks_mut(channels) {
-                    for (sample, val) in frame.iter_mut().zip(values.iter()) {
-                        *sample = *val;
-                    }
-                }
-            },
-            move |err| eprintln!("Audio error: {}", err),
-            None,
-        )
-        .unwrap();
+    let renderer = audio::Renderer::new(
+        cv_consumer,
+        channels,
+        config.sample_rate.0 as f32,
+        cmdline_args.latency_ms,
+    );
+    let stream =
+        audio::build_stream(&audio_device, &config, format, renderer).unwrap_or_else(|e| {
+            eprintln!("Failed to open audio stream: {}", e);
+            process::exit(1);
+        });
 
     stream.play().unwrap();
 
+    if let Some(Command::Calibrate {
+        output,
+        channel,
+        steps,
+    }) = &cmdline_args.command
+    {
+        calibrate::run(cv_events, channels, channel, *steps, output);
+        return;
+    }
+
+    let outputs = config::Outputs {
+        cv_channels: channels,
+        midi_ports: [
+            &cmdline_args.midi_device[..],
+            &cmdline_args.virtual_midi[..],
+        ]
+        .concat(),
+        osc_out: cmdline_args.osc_target.is_some(),
+    };
+    let mapping_config = match &cmdline_args.config {
+        Some(path) => config::load(path, &outputs).unwrap_or_else(|e| {
+            eprintln!("Invalid config: {}", e);
+            process::exit(1);
+        }),
+        None => config::Config {
+            mappings: config::default_mappings(channels),
+            ..Default::default()
+        },
+    };
+    let mapping_config: config::SharedConfig = Arc::new(Mutex::new(Arc::new(mapping_config)));
+    if let Some(path) = &cmdline_args.config {
+        config::watch(path.clone(), outputs, mapping_config.clone());
+    }
+
+    let calibration = match &cmdline_args.calibration {
+        Some(path) => calibration::Calibration::load(path, channels).unwrap_or_else(|e| {
+            eprintln!("Invalid calibration {}: {}", path.display(), e);
+            process::exit(1);
+        }),
+        None => calibration::Calibration::uncalibrated(channels),
+    };
+
+    let mut midi_conns: Vec<MidiOutputConnection> = cmdline_args
+        .midi_device
+        .iter()
+        .map(|name| find_midi_device(Some(name)))
+        .chain(
+            cmdline_args
+                .virtual_midi
+                .iter()
+                .map(|name| create_virtual_midi(name)),
+        )
+        .collect();
+    if midi_conns.is_empty() {
+        midi_conns.push(find_midi_device(None));
+    }
+
+    // OSC packets and translated MIDI input meet in one queue.
+    let (packets, incoming) = mpsc::channel();
     let osc_socket = UdpSocket::bind(format!("0.0.0.0:{}", cmdline_args.osc_port)).unwrap();
     println!("Listening on OSC port {}", cmdline_args.osc_port);
+    let osc_out = cmdline_args.osc_target.map(|target| {
+        println!("Sending OSC to {}", target);
+        (osc_socket.try_clone().unwrap(), target)
+    });
+    osc::listen(osc_socket, packets.clone());
+
+    let _midi_inputs: Vec<MidiInputConnection<()>> = cmdline_args
+        .midi_input
+        .iter()
+        .map(|name| open_midi_input(name, packets.clone()))
+        .collect();
+
+    let mut bridge = bridge::Bridge {
+        config: mapping_config,
+        cv_events,
+        midi_conns,
+        osc_out,
+        midi_limiter: midi::MidiLimiter::default(),
+        calibration,
+        voices: None,
+        clock: clock::Clock::default(),
+        clock_out: None,
+        sync_pll: sync::Pll::default(),
+        sync_ticks: 0,
+        generators: generator::Generators::default(),
+        debug: cmdline_args.debug,
+    };
+    let mut scheduler = osc::Scheduler::default();
+    let mut stats_printed = Instant::now();
 
-    let osc_address_map: HashMap<&str, usize> = [
-        ("/lfo1", 0),
-        ("/lfo2", 1),
-        ("/lfo3", 2),
-        ("/lfo4", 3),
-        ("/stepped32", 4),
-        ("/stepped8", 5),
-    ]
-    .iter()
-    .cloned()
-    .collect();
-
-    let mut buf = [0u8; 1024];
     loop {
-        if let Ok((size, _)) = osc_socket.recv_from(&mut buf) {
-            if let Ok((_, packet)) = decoder::decode_udp(&buf[..size]) {
-                if let OscPacket::Message(OscMessage { addr, args, .. }) = packet {
-                    if let Some(&channel) = osc_address_map.get(addr.as_str()) {
-                        if let Some(rosc::OscType::Float(value)) = args.first() {
-                            let audio_val = value * 2.0 - 1.0;
-                            let midi_val = (value * 127.0).clamp(0.0, 127.0) as u8;
-
-                            {
-                                let mut vals = latest_values.lock().unwrap();
-                                vals[channel] = audio_val;
-                            }
-
-                            let midi_message = [0xB0, channel as u8, midi_val];
-                            midi_conn.lock().unwrap().send(&midi_message).unwrap();
-
-                            if cmdline_args.debug {
-                                println!(
-                                    "{} -> Channel {}: Audio {}, MIDI {}",
-                                    addr,
-                                    channel + 1,
-                                    audio_val,
-                                    midi_val
-                                );
-                            }
-                        }
-                    }
-                }
-            }
+        // Wake up in time for the next scheduled bundle, rate-limited MIDI
+        // value, clock tick or generator value.
+        let timeout = [
+            scheduler.time_until_next(),
+            bridge.time_until_midi(),
+            bridge.time_until_clock(),
+            bridge.time_until_generators(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map(|t| t.max(Duration::from_millis(1)));
+        let received = match timeout {
+            Some(timeout) => incoming.recv_timeout(timeout).ok(),
+            None => incoming.recv().ok(),
+        };
+        for (time, packet) in received.into_iter().chain(incoming.try_iter()) {
+            scheduler.push(packet, time);
+        }
+
+        for (time, message) in scheduler.take_due() {
+            bridge.handle(&message, time);
+        }
+        bridge.flush_midi();
+        bridge.flush_clock();
+        bridge.flush_generators();
+
+        if cmdline_args.debug && stats_printed.elapsed() >= STATS_INTERVAL {
+            let stats = &bridge.midi_limiter.stats;
+            println!(
+                "MIDI: {} sent, {} deduplicated, {} coalesced",
+                stats.sent, stats.deduplicated, stats.coalesced
+            );
+            stats_printed = Instant::now();
         }
     }
 }
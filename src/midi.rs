@@ -0,0 +1,366 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// MIDI message types a mapping can send, as named in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MidiType {
+    Cc,
+    Cc14,
+    Note,
+    PitchBend,
+    ChannelPressure,
+    PolyAftertouch,
+    ProgramChange,
+    Nrpn,
+    Rpn,
+}
+
+/// A MIDI message type along with its controller, note or parameter number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MidiKind {
+    ControlChange(u8),
+    /// A 14-bit controller: MSB on the given number, LSB on number + 32.
+    ControlChange14(u8),
+    /// Note on with the value as velocity, note off at zero.
+    Note(u8),
+    PitchBend,
+    ChannelPressure,
+    PolyAftertouch(u8),
+    ProgramChange,
+    Nrpn(u16),
+    Rpn(u16),
+}
+
+impl MidiKind {
+    /// Combines a type with its number, checking the number is in range.
+    pub fn new(midi_type: MidiType, number: Option<u16>) -> Result<Self, String> {
+        let seven_bit = |name: &str| match number {
+            Some(n) if n <= 127 => Ok(n as u8),
+            Some(n) => Err(format!(
+                "`midi_number` for {} must be 0-127, got {}",
+                name, n
+            )),
+            None => Err(format!("{} needs a `midi_number`", name)),
+        };
+        let fourteen_bit = |name: &str| match number {
+            Some(n) if n <= 16383 => Ok(n),
+            Some(n) => Err(format!(
+                "`midi_number` for {} must be 0-16383, got {}",
+                name, n
+            )),
+            None => Err(format!("{} needs a `midi_number`", name)),
+        };
+        Ok(match midi_type {
+            MidiType::Cc => MidiKind::ControlChange(seven_bit("cc")?),
+            MidiType::Cc14 => match number {
+                Some(n) if n <= 31 => MidiKind::ControlChange14(n as u8),
+                Some(n) => return Err(format!("`midi_number` for cc14 must be 0-31, got {}", n)),
+                None => return Err("cc14 needs a `midi_number`".to_string()),
+            },
+            MidiType::Note => MidiKind::Note(seven_bit("note")?),
+            MidiType::PitchBend => MidiKind::PitchBend,
+            MidiType::ChannelPressure => MidiKind::ChannelPressure,
+            MidiType::PolyAftertouch => MidiKind::PolyAftertouch(seven_bit("poly_aftertouch")?),
+            MidiType::ProgramChange => MidiKind::ProgramChange,
+            MidiType::Nrpn => MidiKind::Nrpn(fourteen_bit("nrpn")?),
+            MidiType::Rpn => MidiKind::Rpn(fourteen_bit("rpn")?),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MidiTarget {
+    /// Zero-based MIDI channel.
+    pub channel: u8,
+    pub kind: MidiKind,
+}
+
+impl MidiTarget {
+    /// Encodes a 0..1 value as the MIDI messages for this target.
+    pub fn messages(&self, value: f32) -> Vec<Vec<u8>> {
+        let value = value.clamp(0.0, 1.0);
+        let seven = (value * 127.0) as u8;
+        // Scaled by 16384 so 0.5 lands on the pitch bend centre, 0x2000.
+        let fourteen = (value * 16384.0).min(16383.0) as u16;
+        let (msb, lsb) = ((fourteen >> 7) as u8, (fourteen & 0x7F) as u8);
+        let ch = self.channel;
+        let cc = |controller: u8, data: u8| vec![0xB0 | ch, controller, data];
+
+        match self.kind {
+            MidiKind::ControlChange(controller) => vec![cc(controller, seven)],
+            MidiKind::ControlChange14(controller) => {
+                vec![cc(controller, msb), cc(controller + 32, lsb)]
+            }
+            MidiKind::Note(note) if seven > 0 => vec![vec![0x90 | ch, note, seven]],
+            MidiKind::Note(note) => vec![vec![0x80 | ch, note, 0]],
+            MidiKind::PitchBend => vec![vec![0xE0 | ch, lsb, msb]],
+            MidiKind::ChannelPressure => vec![vec![0xD0 | ch, seven]],
+            MidiKind::PolyAftertouch(note) => vec![vec![0xA0 | ch, note, seven]],
+            MidiKind::ProgramChange => vec![vec![0xC0 | ch, seven]],
+            MidiKind::Nrpn(param) | MidiKind::Rpn(param) => {
+                let (param_msb, param_lsb) = match self.kind {
+                    MidiKind::Nrpn(_) => (99, 98),
+                    _ => (101, 100),
+                };
+                vec![
+                    cc(param_msb, (param >> 7) as u8),
+                    cc(param_lsb, (param & 0x7F) as u8),
+                    cc(6, msb),
+                    cc(38, lsb),
+                ]
+            }
+        }
+    }
+}
+
+/// How often a target may send and whether repeats are suppressed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    pub min_interval: Option<Duration>,
+    pub dedup: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct MidiStats {
+    pub sent: u64,
+    /// Dropped because the value on the wire was already the same.
+    pub deduplicated: u64,
+    /// Replaced by a newer value before they could be sent.
+    pub coalesced: u64,
+}
+
+#[derive(Default)]
+struct Slot {
+    last_sent: Option<Instant>,
+    last_messages: Vec<Vec<u8>>,
+    pending: Option<Vec<Vec<u8>>>,
+    min_interval: Duration,
+}
+
+/// Keeps slow MIDI ports from backing up. Each target sends at most once per
+/// interval; values arriving in between replace each other and the latest is
+/// sent once the interval has passed. Notes are events rather than values, so
+/// they always go out right away.
+#[derive(Default)]
+pub struct MidiLimiter {
+    /// Keyed by output port and target.
+    slots: HashMap<(usize, MidiTarget), Slot>,
+    pub stats: MidiStats,
+}
+
+impl MidiLimiter {
+    /// Returns the messages to send right away, if any.
+    pub fn submit(
+        &mut self,
+        port: usize,
+        target: MidiTarget,
+        limit: RateLimit,
+        messages: Vec<Vec<u8>>,
+        now: Instant,
+    ) -> Option<Vec<Vec<u8>>> {
+        // Notes are events: repeating one is meaningful, and holding back a
+        // note off until the next note on would leave the note stuck.
+        if matches!(target.kind, MidiKind::Note(_)) {
+            self.stats.sent += 1;
+            return Some(messages);
+        }
+
+        let slot = self.slots.entry((port, target)).or_default();
+        slot.min_interval = limit.min_interval.unwrap_or(Duration::ZERO);
+
+        if limit.dedup && slot.last_messages == messages {
+            if slot.pending.take().is_some() {
+                self.stats.coalesced += 1;
+            }
+            self.stats.deduplicated += 1;
+            return None;
+        }
+
+        let ready = slot
+            .last_sent
+            .is_none_or(|sent| now.duration_since(sent) >= slot.min_interval);
+        if !ready {
+            if slot.pending.replace(messages).is_some() {
+                self.stats.coalesced += 1;
+            }
+            return None;
+        }
+
+        slot.pending = None;
+        slot.last_sent = Some(now);
+        slot.last_messages.clone_from(&messages);
+        self.stats.sent += 1;
+        Some(messages)
+    }
+
+    /// Removes and returns pending messages whose interval has passed, along
+    /// with their output port.
+    pub fn take_due(&mut self, now: Instant) -> Vec<(usize, Vec<u8>)> {
+        let mut due = Vec::new();
+        for (&(port, _), slot) in self.slots.iter_mut() {
+            let ready = slot
+                .last_sent
+                .is_none_or(|sent| now.duration_since(sent) >= slot.min_interval);
+            if ready && let Some(messages) = slot.pending.take() {
+                slot.last_sent = Some(now);
+                slot.last_messages.clone_from(&messages);
+                self.stats.sent += 1;
+                due.extend(messages.into_iter().map(|m| (port, m)));
+            }
+        }
+        due
+    }
+
+    /// Time left until the next pending value may be sent.
+    pub fn time_until_next(&self, now: Instant) -> Option<Duration> {
+        self.slots
+            .values()
+            .filter(|slot| slot.pending.is_some())
+            .map(|slot| match slot.last_sent {
+                Some(sent) => (sent + slot.min_interval).saturating_duration_since(now),
+                None => Duration::ZERO,
+            })
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CC: MidiTarget = MidiTarget {
+        channel: 0,
+        kind: MidiKind::ControlChange(1),
+    };
+    const NOTE: MidiTarget = MidiTarget {
+        channel: 0,
+        kind: MidiKind::Note(60),
+    };
+    const LIMIT: RateLimit = RateLimit {
+        min_interval: Some(Duration::from_millis(10)),
+        dedup: true,
+    };
+
+    fn submit(
+        limiter: &mut MidiLimiter,
+        target: MidiTarget,
+        value: f32,
+        now: Instant,
+    ) -> Option<Vec<Vec<u8>>> {
+        limiter.submit(0, target, LIMIT, target.messages(value), now)
+    }
+
+    fn messages(channel: u8, kind: MidiKind, value: f32) -> Vec<Vec<u8>> {
+        MidiTarget { channel, kind }.messages(value)
+    }
+
+    #[test]
+    fn values_encode_per_kind() {
+        assert_eq!(
+            messages(0, MidiKind::ControlChange(7), 1.0),
+            [[0xB0, 7, 127]]
+        );
+        assert_eq!(
+            messages(1, MidiKind::ControlChange14(1), 0.5),
+            [[0xB1, 1, 64], [0xB1, 33, 0]]
+        );
+        assert_eq!(messages(2, MidiKind::Note(60), 1.0), [[0x92, 60, 127]]);
+        assert_eq!(messages(2, MidiKind::Note(60), 0.0), [[0x82, 60, 0]]);
+        assert_eq!(messages(0, MidiKind::ChannelPressure, 1.0), [[0xD0, 127]]);
+        assert_eq!(
+            messages(0, MidiKind::PolyAftertouch(64), 1.0),
+            [[0xA0, 64, 127]]
+        );
+        assert_eq!(messages(0, MidiKind::ProgramChange, 0.0), [[0xC0, 0]]);
+    }
+
+    #[test]
+    fn pitch_bend_spans_the_full_range() {
+        assert_eq!(messages(0, MidiKind::PitchBend, 0.0), [[0xE0, 0, 0]]);
+        // Centre is 0x2000: LSB first, then MSB.
+        assert_eq!(messages(0, MidiKind::PitchBend, 0.5), [[0xE0, 0, 64]]);
+        assert_eq!(messages(0, MidiKind::PitchBend, 1.0), [[0xE0, 127, 127]]);
+        assert_eq!(messages(0, MidiKind::PitchBend, 2.0), [[0xE0, 127, 127]]);
+    }
+
+    #[test]
+    fn parameter_numbers_select_before_the_value() {
+        let value = [[0xB0, 6, 127], [0xB0, 38, 127]];
+        let nrpn = messages(0, MidiKind::Nrpn(0x1234), 1.0);
+        assert_eq!(
+            nrpn,
+            [[0xB0, 99, 0x24], [0xB0, 98, 0x34], value[0], value[1]]
+        );
+        let rpn = messages(0, MidiKind::Rpn(0), 1.0);
+        assert_eq!(rpn, [[0xB0, 101, 0], [0xB0, 100, 0], value[0], value[1]]);
+    }
+
+    #[test]
+    fn repeated_values_are_dropped() {
+        let mut limiter = MidiLimiter::default();
+        let start = Instant::now();
+        let later = start + Duration::from_millis(20);
+
+        assert!(submit(&mut limiter, CC, 0.5, start).is_some());
+        assert_eq!(submit(&mut limiter, CC, 0.5, later), None);
+        assert_eq!(limiter.stats.deduplicated, 1);
+        assert_eq!(limiter.stats.sent, 1);
+    }
+
+    #[test]
+    fn values_within_the_interval_coalesce() {
+        let mut limiter = MidiLimiter::default();
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+
+        assert!(submit(&mut limiter, CC, 0.0, start).is_some());
+        assert_eq!(submit(&mut limiter, CC, 0.5, ms(2)), None);
+        assert_eq!(submit(&mut limiter, CC, 1.0, ms(4)), None);
+        assert_eq!(limiter.stats.coalesced, 1);
+        assert_eq!(
+            limiter.time_until_next(ms(4)),
+            Some(Duration::from_millis(6))
+        );
+
+        assert!(limiter.take_due(ms(8)).is_empty());
+        assert_eq!(limiter.take_due(ms(10)), [(0, vec![0xB0, 1, 127])]);
+        assert_eq!(limiter.time_until_next(ms(10)), None);
+    }
+
+    #[test]
+    fn returning_to_the_sent_value_cancels_the_pending_one() {
+        let mut limiter = MidiLimiter::default();
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+
+        assert!(submit(&mut limiter, CC, 0.0, start).is_some());
+        assert_eq!(submit(&mut limiter, CC, 0.5, ms(2)), None);
+        assert_eq!(submit(&mut limiter, CC, 0.0, ms(4)), None);
+
+        assert!(limiter.take_due(ms(10)).is_empty());
+    }
+
+    #[test]
+    fn notes_within_the_interval_all_go_out() {
+        let mut limiter = MidiLimiter::default();
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!(
+            submit(&mut limiter, NOTE, 1.0, start),
+            Some(vec![vec![0x90, 60, 127]])
+        );
+        assert_eq!(
+            submit(&mut limiter, NOTE, 0.0, ms(1)),
+            Some(vec![vec![0x80, 60, 0]])
+        );
+        assert_eq!(
+            submit(&mut limiter, NOTE, 1.0, ms(2)),
+            Some(vec![vec![0x90, 60, 127]])
+        );
+        assert_eq!(limiter.stats.coalesced, 0);
+        assert_eq!(limiter.time_until_next(ms(2)), None);
+    }
+}
@@ -0,0 +1,159 @@
+//! Incoming MIDI, translated into OSC messages so the same mappings drive
+//! CV, MIDI and OSC outputs from either source.
+//!
+//! Channels are 1-based and values are scaled to 0..1 like OSC MIDI arguments:
+//!
+//! - `/midi/<ch>/note/<n>`: velocity, 0 on note off
+//! - `/midi/<ch>/note`: note number and velocity, for pitch and gate mappings
+//! - `/midi/<ch>/cc/<n>`: controller value
+//! - `/midi/<ch>/bend`: 14-bit pitch bend, 0.5 at rest
+//! - `/midi/<ch>/pressure`: channel pressure
+//! - `/midi/<ch>/aftertouch/<n>`: polyphonic key pressure
+//!
+//! System real-time messages become `/midi/clock`, `/midi/start`,
+//! `/midi/stop` and `/midi/continue`, and Song Position Pointer becomes
+//! `/midi/position` with the position in 16th notes, for clock sync.
+
+use rosc::{OscMessage, OscType};
+
+/// Translates one MIDI message, empty for messages without a mapping address.
+pub fn to_osc(bytes: &[u8]) -> Vec<OscMessage> {
+    let Some((&status, data)) = bytes.split_first() else {
+        return Vec::new();
+    };
+    let ch = (status & 0x0F) + 1;
+    let data1 = data.first().copied().unwrap_or(0);
+    let data2 = data.get(1).copied().unwrap_or(0);
+    let message = |addr: String, args: Vec<OscType>| OscMessage { addr, args };
+    let seven = |data: u8| OscType::Float(data as f32 / 127.0);
+
+    match status & 0xF0 {
+        0x80 | 0x90 => {
+            // Note off reads as velocity 0, the same as a note on at velocity 0.
+            let velocity = if status & 0xF0 == 0x80 { 0 } else { data2 };
+            vec![
+                message(
+                    format!("/midi/{}/note/{}", ch, data1),
+                    vec![seven(velocity)],
+                ),
+                message(
+                    format!("/midi/{}/note", ch),
+                    vec![OscType::Int(data1 as i32), seven(velocity)],
+                ),
+            ]
+        }
+        0xA0 => vec![message(
+            format!("/midi/{}/aftertouch/{}", ch, data1),
+            vec![seven(data2)],
+        )],
+        0xB0 => vec![message(
+            format!("/midi/{}/cc/{}", ch, data1),
+            vec![seven(data2)],
+        )],
+        0xD0 => vec![message(
+            format!("/midi/{}/pressure", ch),
+            vec![seven(data1)],
+        )],
+        0xE0 => {
+            let bend = (data2 as u16) << 7 | data1 as u16;
+            vec![message(
+                format!("/midi/{}/bend", ch),
+                vec![OscType::Float(bend as f32 / 16383.0)],
+            )]
+        }
+        0xF0 => {
+            let realtime = |name: &str| vec![message(format!("/midi/{}", name), Vec::new())];
+            match status {
+                0xF2 => vec![message(
+                    "/midi/position".to_string(),
+                    vec![OscType::Int(((data2 as i32) << 7) | data1 as i32)],
+                )],
+                0xF8 => realtime("clock"),
+                0xFA => realtime("start"),
+                0xFB => realtime("continue"),
+                0xFC => realtime("stop"),
+                _ => Vec::new(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate(bytes: &[u8]) -> Vec<(String, Vec<OscType>)> {
+        to_osc(bytes)
+            .into_iter()
+            .map(|message| (message.addr, message.args))
+            .collect()
+    }
+
+    #[test]
+    fn note_offs_read_as_velocity_zero() {
+        let note = |velocity| {
+            vec![
+                (
+                    "/midi/2/note/60".to_string(),
+                    vec![OscType::Float(velocity)],
+                ),
+                (
+                    "/midi/2/note".to_string(),
+                    vec![OscType::Int(60), OscType::Float(velocity)],
+                ),
+            ]
+        };
+        assert_eq!(translate(&[0x91, 60, 127]), note(1.0));
+        assert_eq!(translate(&[0x81, 60, 64]), note(0.0));
+        assert_eq!(translate(&[0x91, 60, 0]), note(0.0));
+    }
+
+    #[test]
+    fn bend_carries_fourteen_bits() {
+        let bend = |lsb, msb| match &translate(&[0xE0, lsb, msb])[..] {
+            [(addr, args)] if addr == "/midi/1/bend" => args.clone(),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(bend(0, 0), [OscType::Float(0.0)]);
+        assert_eq!(bend(0x7F, 0x7F), [OscType::Float(1.0)]);
+        assert_eq!(bend(0, 0x40), [OscType::Float(8192.0 / 16383.0)]);
+    }
+
+    #[test]
+    fn system_messages_drive_the_clock() {
+        assert_eq!(
+            translate(&[0xF2, 0x01, 0x02]),
+            [("/midi/position".to_string(), vec![OscType::Int(0x101)])]
+        );
+        for (status, addr) in [
+            (0xF8, "/midi/clock"),
+            (0xFA, "/midi/start"),
+            (0xFB, "/midi/continue"),
+            (0xFC, "/midi/stop"),
+        ] {
+            assert_eq!(translate(&[status]), [(addr.to_string(), Vec::new())]);
+        }
+        assert!(translate(&[0xFE]).is_empty());
+        assert!(translate(&[]).is_empty());
+    }
+
+    #[test]
+    fn controllers_and_pressure() {
+        assert_eq!(
+            translate(&[0xBF, 1, 127]),
+            [("/midi/16/cc/1".to_string(), vec![OscType::Float(1.0)])]
+        );
+        assert_eq!(
+            translate(&[0xD0, 0]),
+            [("/midi/1/pressure".to_string(), vec![OscType::Float(0.0)])]
+        );
+        assert_eq!(
+            translate(&[0xA0, 64, 127]),
+            [(
+                "/midi/1/aftertouch/64".to_string(),
+                vec![OscType::Float(1.0)]
+            )]
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use crate::config::InputKind;
+use rosc::{OscMessage, OscPacket, OscTime, OscType, decoder};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::net::UdpSocket;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The OSC timetag meaning "process immediately".
+const IMMEDIATELY: OscTime = OscTime {
+    seconds: 0,
+    fractional: 1,
+};
+
+/// Seconds from the OSC epoch, 1900, to the Unix epoch. Earlier timetags
+/// don't fit a `SystemTime`.
+const UNIX_EPOCH_SECONDS: u32 = 2_208_988_800;
+
+struct Scheduled {
+    due: SystemTime,
+    seq: u64,
+    message: OscMessage,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.due, self.seq).cmp(&(other.due, other.seq))
+    }
+}
+
+/// Holds incoming messages until their bundle timetag is reached.
+/// Messages with the same due time are released in arrival order.
+#[derive(Default)]
+pub struct Scheduler {
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    seq: u64,
+}
+
+impl Scheduler {
+    /// Queues every message in a packet, recursing into nested bundles.
+    /// Messages outside of a future bundle are due on arrival.
+    pub fn push(&mut self, packet: OscPacket, received: SystemTime) {
+        self.push_at(packet, received);
+    }
+
+    fn push_at(&mut self, packet: OscPacket, due: SystemTime) {
+        match packet {
+            OscPacket::Message(message) => {
+                self.seq += 1;
+                self.queue.push(Reverse(Scheduled {
+                    due,
+                    seq: self.seq,
+                    message,
+                }));
+            }
+            OscPacket::Bundle(bundle) => {
+                // Timetags before 1970 are long past, so they are due on
+                // arrival too.
+                let due = if bundle.timetag == IMMEDIATELY
+                    || bundle.timetag.seconds < UNIX_EPOCH_SECONDS
+                {
+                    due
+                } else {
+                    // Nested bundles may not be scheduled before their parent.
+                    due.max(bundle.timetag.into())
+                };
+                for packet in bundle.content {
+                    self.push_at(packet, due);
+                }
+            }
+        }
+    }
+
+    /// Time left until the next queued message is due, `None` if nothing is queued.
+    pub fn time_until_next(&self) -> Option<Duration> {
+        self.queue.peek().map(|Reverse(next)| {
+            next.due
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO)
+        })
+    }
+
+    /// Removes and returns all messages that are due, with the time they were
+    /// meant for.
+    pub fn take_due(&mut self) -> Vec<(Instant, OscMessage)> {
+        let now = SystemTime::now();
+        let now_instant = Instant::now();
+        let mut due = Vec::new();
+        while self
+            .queue
+            .peek()
+            .is_some_and(|Reverse(next)| next.due <= now)
+        {
+            let Reverse(next) = self.queue.pop().unwrap();
+            let late = now.duration_since(next.due).unwrap_or(Duration::ZERO);
+            due.push((now_instant - late, next.message));
+        }
+        due
+    }
+}
+
+/// Receives and decodes packets on a background thread, stamped with their
+/// arrival time. Undecodable packets are dropped.
+pub fn listen(socket: UdpSocket, packets: Sender<(SystemTime, OscPacket)>) {
+    thread::spawn(move || {
+        let mut buf = [0u8; decoder::MTU];
+        loop {
+            if let Ok((size, _)) = socket.recv_from(&mut buf)
+                && let Ok((_, packet)) = decoder::decode_udp(&buf[..size])
+                && packets.send((SystemTime::now(), packet)).is_err()
+            {
+                return;
+            }
+        }
+    });
+}
+
+/// Coerces an OSC argument into a value, `None` if the type carries no number
+/// or the number is NaN or infinite.
+pub fn arg_value(arg: &OscType, input: InputKind) -> Option<f32> {
+    let value = match arg {
+        OscType::Float(v) => *v,
+        OscType::Double(v) => *v as f32,
+        OscType::Int(v) => *v as f32,
+        OscType::Long(v) => *v as f32,
+        OscType::Bool(v) => *v as u8 as f32,
+        OscType::Midi(midi) => match midi.status & 0xF0 {
+            // Pitch bend carries a 14-bit value, LSB first.
+            0xE0 => ((midi.data2 as u16) << 7 | midi.data1 as u16) as f32 / 16383.0,
+            // Note on/off, poly pressure, control change: the second data byte.
+            0x80 | 0x90 | 0xA0 | 0xB0 => midi.data2 as f32 / 127.0,
+            // Program change, channel pressure: the only data byte.
+            0xC0 | 0xD0 => midi.data1 as f32 / 127.0,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    // NaN would stick to slewed channels and reach the device as samples.
+    if !value.is_finite() {
+        return None;
+    }
+    match input {
+        InputKind::Auto => Some(value),
+        InputKind::Boolean => Some(if value != 0.0 { 1.0 } else { 0.0 }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rosc::{OscBundle, OscMidiMessage};
+
+    fn message(addr: &str) -> OscPacket {
+        OscPacket::Message(OscMessage {
+            addr: addr.to_string(),
+            args: Vec::new(),
+        })
+    }
+
+    fn bundle(timetag: OscTime, content: Vec<OscPacket>) -> OscPacket {
+        OscPacket::Bundle(OscBundle { timetag, content })
+    }
+
+    fn addresses(due: Vec<(Instant, OscMessage)>) -> Vec<String> {
+        due.into_iter().map(|(_, message)| message.addr).collect()
+    }
+
+    #[test]
+    fn timetags_before_1970_are_due_on_arrival() {
+        let mut scheduler = Scheduler::default();
+        let zero = OscTime {
+            seconds: 0,
+            fractional: 0,
+        };
+        scheduler.push(bundle(zero, vec![message("/a")]), SystemTime::now());
+
+        assert_eq!(addresses(scheduler.take_due()), ["/a"]);
+        assert_eq!(scheduler.time_until_next(), None);
+    }
+
+    #[test]
+    fn nested_bundles_wait_for_their_own_timetag() {
+        let mut scheduler = Scheduler::default();
+        let past = OscTime {
+            seconds: UNIX_EPOCH_SECONDS + 1,
+            fractional: 0,
+        };
+        let future = OscTime::try_from(SystemTime::now() + Duration::from_secs(3600)).unwrap();
+        scheduler.push(
+            bundle(
+                past,
+                vec![
+                    message("/now"),
+                    bundle(future, vec![message("/later")]),
+                    message("/also-now"),
+                ],
+            ),
+            SystemTime::now(),
+        );
+
+        assert_eq!(addresses(scheduler.take_due()), ["/now", "/also-now"]);
+        assert!(
+            scheduler
+                .time_until_next()
+                .is_some_and(|left| left > Duration::from_secs(3500))
+        );
+    }
+
+    #[test]
+    fn arguments_coerce_into_values() {
+        let midi = |status, data1, data2| {
+            OscType::Midi(OscMidiMessage {
+                port: 0,
+                status,
+                data1,
+                data2,
+            })
+        };
+        for (arg, expected) in [
+            (OscType::Float(0.25), Some(0.25)),
+            (OscType::Double(-2.5), Some(-2.5)),
+            (OscType::Int(7), Some(7.0)),
+            (OscType::Long(-3), Some(-3.0)),
+            (OscType::Bool(true), Some(1.0)),
+            (midi(0xB0, 1, 127), Some(1.0)),
+            (midi(0xE0, 0, 0x40), Some(8192.0 / 16383.0)),
+            (midi(0xC0, 127, 0), Some(1.0)),
+            (midi(0xF8, 0, 0), None),
+            (OscType::String("1".to_string()), None),
+            (OscType::Float(f32::NAN), None),
+            (OscType::Float(f32::INFINITY), None),
+            (OscType::Double(f64::NEG_INFINITY), None),
+            (OscType::Double(1e300), None),
+        ] {
+            assert_eq!(arg_value(&arg, InputKind::Auto), expected, "{:?}", arg);
+        }
+        assert_eq!(
+            arg_value(&OscType::Float(0.3), InputKind::Boolean),
+            Some(1.0)
+        );
+        assert_eq!(arg_value(&OscType::Int(0), InputKind::Boolean), Some(0.0));
+        assert_eq!(
+            arg_value(&OscType::Float(f32::NAN), InputKind::Boolean),
+            None
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! OSC 1.0 address pattern matching: `*`, `?`, `[a-z]`, `[!a-z]` and `{foo,bar}`.
+
+/// Checks that brackets and braces in a pattern are balanced and not nested.
+pub fn validate(pattern: &str) -> Result<(), String> {
+    let mut open: Option<char> = None;
+    for c in pattern.chars() {
+        match (open, c) {
+            (None, '[') | (None, '{') => open = Some(c),
+            (Some('['), ']') | (Some('{'), '}') => open = None,
+            (Some(_), '[' | '{' | '/') => return Err(format!("unterminated `{}`", open.unwrap())),
+            (None, ']' | '}') => return Err(format!("unbalanced `{}`", c)),
+            _ => {}
+        }
+    }
+    match open {
+        Some(c) => Err(format!("unterminated `{}`", c)),
+        None => Ok(()),
+    }
+}
+
+/// Matches two addresses where either side may be a pattern, so that both
+/// pattern mapping keys and pattern messages reach literal counterparts.
+pub fn addresses_match(a: &str, b: &str) -> bool {
+    a == b || matches(a, b) || matches(b, a)
+}
+
+/// Matches a literal OSC address against a pattern, part by part.
+pub fn matches(pattern: &str, address: &str) -> bool {
+    let pattern_parts: Vec<&str> = pattern.split('/').collect();
+    let address_parts: Vec<&str> = address.split('/').collect();
+    pattern_parts.len() == address_parts.len()
+        && pattern_parts.iter().zip(&address_parts).all(|(p, a)| {
+            let p: Vec<char> = p.chars().collect();
+            let a: Vec<char> = a.chars().collect();
+            match_part(&p, &a)
+        })
+}
+
+/// Matches one part of an address. Results are memoised per position, so
+/// patterns with many `*` take polynomial rather than exponential time.
+fn match_part(pattern: &[char], name: &[char]) -> bool {
+    let mut memo = vec![None; (pattern.len() + 1) * (name.len() + 1)];
+    match_from(pattern, name, 0, 0, &mut memo)
+}
+
+fn match_from(
+    pattern: &[char],
+    name: &[char],
+    p: usize,
+    n: usize,
+    memo: &mut [Option<bool>],
+) -> bool {
+    let key = p * (name.len() + 1) + n;
+    if let Some(matched) = memo[key] {
+        return matched;
+    }
+    let rest = &name[n..];
+    let matched = match pattern.get(p) {
+        None => rest.is_empty(),
+        Some('*') => (n..=name.len()).any(|skip| match_from(pattern, name, p + 1, skip, memo)),
+        Some('?') => !rest.is_empty() && match_from(pattern, name, p + 1, n + 1, memo),
+        Some('[') => match pattern[p + 1..].iter().position(|&c| c == ']') {
+            Some(end) => {
+                !rest.is_empty()
+                    && char_class_matches(&pattern[p + 1..p + 1 + end], rest[0])
+                    && match_from(pattern, name, p + end + 2, n + 1, memo)
+            }
+            None => false,
+        },
+        Some('{') => match pattern[p + 1..].iter().position(|&c| c == '}') {
+            Some(end) => {
+                let after = p + end + 2;
+                pattern[p + 1..p + 1 + end].split(|&c| c == ',').any(|alt| {
+                    rest.starts_with(alt) && match_from(pattern, name, after, n + alt.len(), memo)
+                })
+            }
+            None => false,
+        },
+        Some(&c) => rest.first() == Some(&c) && match_from(pattern, name, p + 1, n + 1, memo),
+    };
+    memo[key] = Some(matched);
+    matched
+}
+
+fn char_class_matches(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.split_first() {
+        Some(('!', rest)) => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            found |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_match_addresses() {
+        for (pattern, address, expected) in [
+            ("/a/b", "/a/b", true),
+            ("/a/b", "/a/c", false),
+            ("/a/*", "/a/xyz", true),
+            ("/a/*", "/a/", true),
+            ("/a/*", "/a/b/c", false),
+            ("/fader*/value", "/fader12/value", true),
+            ("/*/value", "/fader/level", false),
+            ("/a?c", "/abc", true),
+            ("/a?c", "/ac", false),
+            ("/ch[1-3]", "/ch2", true),
+            ("/ch[1-3]", "/ch4", false),
+            ("/ch[137]", "/ch7", true),
+            ("/ch[!1-3]", "/ch4", true),
+            ("/ch[!1-3]", "/ch2", false),
+            ("/ch[1-3]", "/ch", false),
+            ("/ch[a-]", "/ch-", true),
+            ("/{foo,bar}/x", "/bar/x", true),
+            ("/{foo,bar}/x", "/baz/x", false),
+            ("/{a,ab}c", "/abc", true),
+            ("/{,x}y", "/y", true),
+            ("/a/b", "/a/b/c", false),
+        ] {
+            assert_eq!(
+                matches(pattern, address),
+                expected,
+                "{} against {}",
+                pattern,
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn many_stars_match_quickly() {
+        let started = std::time::Instant::now();
+        let name = format!("/{}", "a".repeat(200));
+        assert!(!matches(&format!("/{}b", "*".repeat(100)), &name));
+        assert!(!addresses_match(&name, "/************b"));
+        assert!(matches(&format!("/{}a", "*".repeat(100)), &name));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn either_side_may_be_the_pattern() {
+        for (a, b, expected) in [
+            ("/a/*", "/a/b", true),
+            ("/a/b", "/a/*", true),
+            ("/a/{b,c}", "/a/c", true),
+            ("/a/[!b]", "/a/b", false),
+            ("/a/b", "/a/[!b]", false),
+            // Two patterns only match when equal, or when one reads the
+            // other literally.
+            ("/a/*", "/a/*", true),
+            ("/a/?", "/a/*", true),
+            ("/a/[bc]", "/a/{b,c}", false),
+        ] {
+            assert_eq!(addresses_match(a, b), expected, "{} and {}", a, b);
+        }
+    }
+
+    #[test]
+    fn malformed_patterns_are_rejected() {
+        for pattern in ["/a/*", "/a/[a-z]", "/a/[!0-9]/{x,y}", "/plain"] {
+            assert_eq!(validate(pattern), Ok(()), "{}", pattern);
+        }
+        for (pattern, error) in [
+            ("/a/[ab", "unterminated `[`"),
+            ("/a/{x,y", "unterminated `{`"),
+            ("/a/[a/b]", "unterminated `[`"),
+            ("/a/{x,[y]}", "unterminated `{`"),
+            ("/a/b]", "unbalanced `]`"),
+            ("/a/b}", "unbalanced `}`"),
+        ] {
+            assert_eq!(validate(pattern), Err(error.to_string()), "{}", pattern);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use serde::Deserialize;
+
+/// What a pitch mapping receives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PitchInput {
+    /// MIDI note numbers, fractional for microtones.
+    #[default]
+    Note,
+    /// Frequency in Hz.
+    Hz,
+}
+
+/// The pitch standard of the receiving oscillator.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub enum PitchScale {
+    /// The root note sits at 0 V, each volt is an octave up.
+    #[default]
+    #[serde(rename = "1v/oct")]
+    VoltPerOctave,
+    /// The root note sits at 1 V, voltage is proportional to frequency.
+    #[serde(rename = "hz/v")]
+    HzPerVolt,
+}
+
+pub fn hz_to_note(hz: f32) -> f32 {
+    69.0 + 12.0 * (hz / 440.0).log2()
+}
+
+/// Converts a pitch to the voltage the oscillator expects.
+pub fn volts(value: f32, input: PitchInput, scale: PitchScale, root_note: f32) -> f32 {
+    let note = match input {
+        PitchInput::Note => value,
+        PitchInput::Hz => hz_to_note(value.max(f32::MIN_POSITIVE)),
+    };
+    let octaves = (note - root_note) / 12.0;
+    match scale {
+        PitchScale::VoltPerOctave => octaves,
+        PitchScale::HzPerVolt => octaves.exp2(),
+    }
+}
@@ -0,0 +1,60 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlewMode {
+    /// Constant rate of change; the time is a full -1..1 swing.
+    #[default]
+    Linear,
+    /// One-pole smoothing; the time is the time constant.
+    Exponential,
+}
+
+/// Per-channel smoothing of CV steps. Zero times pass values straight through.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Slew {
+    pub mode: SlewMode,
+    pub rise_ms: f32,
+    pub fall_ms: f32,
+}
+
+/// A slew converted to per-sample amounts for one sample rate.
+#[derive(Debug, Clone, Copy)]
+pub struct SlewRates {
+    mode: SlewMode,
+    rise: f32,
+    fall: f32,
+}
+
+impl Slew {
+    pub fn rates(&self, sample_rate: f32) -> SlewRates {
+        let per_sample = |ms: f32| {
+            let samples = ms * sample_rate / 1000.0;
+            match self.mode {
+                _ if samples <= 0.0 => f32::INFINITY,
+                SlewMode::Linear => 2.0 / samples,
+                SlewMode::Exponential => 1.0 - (-1.0 / samples).exp(),
+            }
+        };
+        SlewRates {
+            mode: self.mode,
+            rise: per_sample(self.rise_ms),
+            fall: per_sample(self.fall_ms),
+        }
+    }
+}
+
+impl SlewRates {
+    /// Moves `current` one sample towards `target`.
+    pub fn step(&self, current: f32, target: f32) -> f32 {
+        let delta = target - current;
+        let rate = if delta > 0.0 { self.rise } else { self.fall };
+        if rate.is_infinite() {
+            return target;
+        }
+        match self.mode {
+            SlewMode::Linear => current + delta.clamp(-rate, rate),
+            SlewMode::Exponential => current + delta * rate,
+        }
+    }
+}
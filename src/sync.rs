@@ -0,0 +1,212 @@
+//! Following an external tempo: MIDI clock ticks or OSC beat positions,
+//! smoothed by a phase-locked loop.
+
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// Where the clock takes its tempo from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncSource {
+    /// `/tempo` messages.
+    #[default]
+    Internal,
+    /// MIDI clock, Start/Stop/Continue and Song Position Pointer on the
+    /// `--midi-input` ports.
+    Midi,
+    /// `/sync/beat <beat> [<bpm>]` messages, e.g. from a DAW.
+    Osc,
+}
+
+/// How much of the phase error each observation corrects.
+const PHASE_GAIN: f64 = 0.1;
+/// How much of the phase error each observation turns into a tempo change.
+const FREQUENCY_GAIN: f64 = 0.005;
+/// A larger error means the reference jumped, so the loop locks afresh.
+const MAX_ERROR_BEATS: f64 = 0.5;
+/// A longer silence means the reference went away.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Estimates the reference's beat position and tempo from timestamped beat
+/// observations. Each observation nudges the estimate rather than replacing
+/// it, so timing jitter is filtered out while tempo changes are followed.
+#[derive(Default)]
+pub struct Pll {
+    /// The estimated beat at the last observation.
+    anchor: Option<(Instant, f64)>,
+    bpm: Option<f64>,
+}
+
+impl Pll {
+    /// Feeds the reference's beat at `time`, with its tempo if it sent one.
+    /// Returns the smoothed beat and tempo at `time` once the tempo is known.
+    /// Non-finite beats are ignored, as are tempos that aren't positive.
+    pub fn observe(
+        &mut self,
+        time: Instant,
+        beat: f64,
+        hint_bpm: Option<f64>,
+    ) -> Option<(f64, f64)> {
+        if !beat.is_finite() {
+            return None;
+        }
+        let hint_bpm = hint_bpm.filter(|bpm| bpm.is_finite() && *bpm > 0.0);
+        let Some((at, estimate)) = self.anchor.filter(|&(at, _)| time - at < TIMEOUT) else {
+            self.lock(time, beat, hint_bpm);
+            return None;
+        };
+        if time <= at {
+            // Nothing to measure without time passing, e.g. two beats released
+            // by the same bundle, but the tempo still holds.
+            self.lock(time, beat, hint_bpm.or(self.bpm));
+            return None;
+        }
+        let elapsed = (time - at).as_secs_f64();
+        let Some(bpm) = self.bpm else {
+            // The first interval gives the starting tempo.
+            let bpm = hint_bpm.unwrap_or((beat - estimate) / elapsed * 60.0);
+            self.lock(
+                time,
+                beat,
+                Some(bpm).filter(|bpm| bpm.is_finite() && *bpm > 0.0),
+            );
+            return self.bpm.map(|bpm| (beat, bpm));
+        };
+
+        let predicted = estimate + elapsed * bpm / 60.0;
+        let error = beat - predicted;
+        if error.abs() > MAX_ERROR_BEATS {
+            self.lock(time, beat, hint_bpm.or(Some(bpm)));
+            return self.bpm.map(|bpm| (beat, bpm));
+        }
+        let beat = predicted + PHASE_GAIN * error;
+        let bpm = bpm + FREQUENCY_GAIN * error / elapsed * 60.0;
+        self.anchor = Some((time, beat));
+        self.bpm = Some(bpm);
+        Some((beat, bpm))
+    }
+
+    /// Moves the estimate along when the reference's position jumps, e.g. on
+    /// MIDI Start or Song Position Pointer, keeping the tempo locked.
+    pub fn shift(&mut self, beats: f64) {
+        if let Some((_, beat)) = &mut self.anchor {
+            *beat += beats;
+        }
+    }
+
+    fn lock(&mut self, time: Instant, beat: f64, bpm: Option<f64>) {
+        self.anchor = Some((time, beat));
+        self.bpm = bpm;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Observes the beats of a steady reference at `bpm`, 24 per beat like
+    /// MIDI clock, for `secs`, with `jitter` seconds added to every other one.
+    /// Returns the last estimate and the reference beat it was for.
+    fn run(
+        pll: &mut Pll,
+        start: Instant,
+        bpm: f64,
+        secs: f64,
+        jitter: f64,
+    ) -> (Option<(f64, f64)>, f64) {
+        let ticks = (secs * bpm / 60.0 * 24.0) as u32;
+        let mut last = (None, 0.0);
+        for tick in 0..ticks {
+            let beat = tick as f64 / 24.0;
+            let late = if tick % 2 == 1 { jitter } else { 0.0 };
+            let time = start + Duration::from_secs_f64(beat * 60.0 / bpm + late);
+            last = (pll.observe(time, beat, None), beat);
+        }
+        last
+    }
+
+    #[test]
+    fn locks_onto_a_steady_tempo() {
+        let mut pll = Pll::default();
+        let start = Instant::now();
+        pll.observe(start, 0.0, Some(120.0));
+        let tick = Duration::from_secs_f64(60.0 / 130.0 / 24.0);
+        let (estimate, beat) = run(&mut pll, start + tick, 130.0, 60.0, 0.0);
+
+        let (estimate_beat, bpm) = estimate.unwrap();
+        assert!((bpm - 130.0).abs() < 0.1, "{} BPM", bpm);
+        assert!((estimate_beat - beat).abs() < 0.01);
+    }
+
+    #[test]
+    fn smooths_out_jitter() {
+        let mut pll = Pll::default();
+        let (estimate, beat) = run(&mut pll, Instant::now(), 120.0, 30.0, 0.003);
+
+        let (estimate_beat, bpm) = estimate.unwrap();
+        // 3 ms at 120 BPM is 0.006 beats; the estimate moves far less.
+        assert!((bpm - 120.0).abs() < 0.5, "{} BPM", bpm);
+        assert!((estimate_beat - beat).abs() < 0.006);
+    }
+
+    #[test]
+    fn relocks_when_the_reference_jumps() {
+        let mut pll = Pll::default();
+        let start = Instant::now();
+        pll.observe(start, 0.0, Some(120.0));
+        let time = start + Duration::from_millis(500);
+
+        assert_eq!(pll.observe(time, 8.0, None), Some((8.0, 120.0)));
+    }
+
+    #[test]
+    fn shift_follows_a_position_change() {
+        let mut pll = Pll::default();
+        let start = Instant::now();
+        pll.observe(start, 0.0, Some(120.0));
+        pll.shift(16.0);
+        let time = start + Duration::from_millis(500);
+
+        let (beat, bpm) = pll.observe(time, 17.0, None).unwrap();
+        assert!((beat - 17.0).abs() < 1e-9);
+        assert!((bpm - 120.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn simultaneous_beats_keep_the_tempo() {
+        let mut pll = Pll::default();
+        let start = Instant::now();
+        pll.observe(start, 0.0, Some(120.0));
+        assert_eq!(pll.observe(start, 0.0, None), None);
+
+        // A late beat nudges the held tempo rather than measuring a new one.
+        let time = start + Duration::from_millis(500);
+        let (_, bpm) = pll.observe(time, 1.1, None).unwrap();
+        assert!((bpm - 120.0).abs() < 0.1, "{} BPM", bpm);
+    }
+
+    #[test]
+    fn non_finite_input_leaves_the_lock_alone() {
+        let mut pll = Pll::default();
+        let start = Instant::now();
+        pll.observe(start, 0.0, Some(120.0));
+        let ms = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!(pll.observe(ms(250), f64::NAN, None), None);
+        assert_eq!(pll.observe(ms(250), f64::INFINITY, Some(120.0)), None);
+        let (beat, bpm) = pll.observe(ms(500), 1.0, Some(f64::NAN)).unwrap();
+        assert!((beat - 1.0).abs() < 1e-9);
+        assert!((bpm - 120.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn silence_drops_the_lock() {
+        let mut pll = Pll::default();
+        let start = Instant::now();
+        pll.observe(start, 0.0, Some(120.0));
+        let time = start + TIMEOUT;
+
+        assert_eq!(pll.observe(time, 4.0, None), None);
+        assert_eq!(pll.bpm, None);
+    }
+}
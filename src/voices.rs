@@ -0,0 +1,323 @@
+//! Polyphonic note-to-CV voice allocation.
+
+use crate::pitch::PitchScale;
+use crate::slew::Slew;
+use serde::Deserialize;
+
+/// Which held notes sound when more are held than there are voices, and
+/// which free voice a new note takes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Priority {
+    /// The most recent notes, each on the voice after the one used last.
+    #[default]
+    RoundRobin,
+    /// The lowest notes, on the lowest free voice.
+    Lowest,
+    /// The highest notes, on the lowest free voice.
+    Highest,
+    /// The most recent notes, on the lowest free voice.
+    Last,
+}
+
+/// The CV channels a voice plays on, zero-based.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoiceGroup {
+    pub pitch: usize,
+    pub gate: usize,
+    pub velocity: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoiceConfig {
+    /// OSC address or pattern of the note messages, `<note> <velocity>`.
+    pub address: String,
+    pub priority: Priority,
+    /// All voices play the one note picked by the priority.
+    pub unison: bool,
+    pub groups: Vec<VoiceGroup>,
+    pub pitch_scale: PitchScale,
+    /// MIDI note at 0 V for 1V/oct, at 1 V for Hz/V.
+    pub root_note: f32,
+    /// Slew on the pitch channels.
+    pub glide: Slew,
+}
+
+/// A change to one voice. Releasing only closes the gate, pitch and velocity
+/// hold so envelopes can finish.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoiceEvent {
+    On { note: f32, velocity: f32 },
+    Off,
+}
+
+pub struct VoiceAllocator {
+    pub config: VoiceConfig,
+    /// Held notes with their velocity, oldest first.
+    held: Vec<(f32, f32)>,
+    /// The note each voice is playing.
+    voices: Vec<Option<f32>>,
+    next: usize,
+}
+
+impl VoiceAllocator {
+    pub fn new(config: VoiceConfig) -> Self {
+        let voices = vec![None; config.groups.len()];
+        VoiceAllocator {
+            config,
+            held: Vec::new(),
+            voices,
+            next: 0,
+        }
+    }
+
+    /// Handles a note on, or a note off at velocity zero, and returns the
+    /// voices that changed. A held note that gets its voice back after a
+    /// release plays again; a voice moving straight to a new note stays open.
+    pub fn note(&mut self, note: f32, velocity: f32) -> Vec<(usize, VoiceEvent)> {
+        self.held.retain(|&(n, _)| n != note);
+        let pressed = velocity > 0.0;
+        if pressed {
+            self.held.push((note, velocity));
+        }
+        let sounding = self.sounding();
+        let restrike = |n: f32| pressed && n == note;
+
+        let mut changes = Vec::new();
+        if self.config.unison {
+            for (i, voice) in self.voices.iter_mut().enumerate() {
+                match sounding.first() {
+                    Some(&(n, v)) if *voice != Some(n) || restrike(n) => {
+                        *voice = Some(n);
+                        changes.push((
+                            i,
+                            VoiceEvent::On {
+                                note: n,
+                                velocity: v,
+                            },
+                        ));
+                    }
+                    None if voice.is_some() => {
+                        *voice = None;
+                        changes.push((i, VoiceEvent::Off));
+                    }
+                    _ => {}
+                }
+            }
+            return changes;
+        }
+
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            if voice.is_some_and(|n| !sounding.iter().any(|&(s, _)| s == n)) {
+                *voice = None;
+                changes.push((i, VoiceEvent::Off));
+            }
+        }
+        for (n, v) in sounding {
+            let i = match self.voices.iter().position(|&voice| voice == Some(n)) {
+                Some(i) if restrike(n) => i,
+                Some(_) => continue,
+                None => {
+                    let Some(i) = self.free_voice() else {
+                        continue;
+                    };
+                    self.voices[i] = Some(n);
+                    i
+                }
+            };
+            changes.retain(|&(voice, _)| voice != i);
+            changes.push((
+                i,
+                VoiceEvent::On {
+                    note: n,
+                    velocity: v,
+                },
+            ));
+        }
+        changes
+    }
+
+    /// Closes every open gate, for when the allocator is replaced.
+    pub fn release_all(&mut self) -> Vec<(usize, VoiceEvent)> {
+        self.held.clear();
+        self.voices
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, voice)| voice.take().map(|_| (i, VoiceEvent::Off)))
+            .collect()
+    }
+
+    /// The held notes that get a voice, according to the priority.
+    fn sounding(&self) -> Vec<(f32, f32)> {
+        let count = if self.config.unison {
+            1
+        } else {
+            self.voices.len()
+        };
+        let mut notes = self.held.clone();
+        match self.config.priority {
+            Priority::RoundRobin | Priority::Last => {
+                notes.drain(..notes.len().saturating_sub(count));
+                // Most recent first, so unison plays the last note.
+                notes.reverse();
+            }
+            Priority::Lowest => notes.sort_by(|a, b| a.0.total_cmp(&b.0)),
+            Priority::Highest => notes.sort_by(|a, b| b.0.total_cmp(&a.0)),
+        }
+        notes.truncate(count);
+        notes
+    }
+
+    fn free_voice(&mut self) -> Option<usize> {
+        let count = self.voices.len();
+        let start = match self.config.priority {
+            Priority::RoundRobin => self.next,
+            _ => 0,
+        };
+        let i = (0..count)
+            .map(|k| (start + k) % count)
+            .find(|&i| self.voices[i].is_none())?;
+        self.next = (i + 1) % count;
+        Some(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocator(priority: Priority, voices: usize, unison: bool) -> VoiceAllocator {
+        VoiceAllocator::new(VoiceConfig {
+            address: "/note".to_string(),
+            priority,
+            unison,
+            groups: (0..voices)
+                .map(|i| VoiceGroup {
+                    pitch: 2 * i,
+                    gate: 2 * i + 1,
+                    velocity: None,
+                })
+                .collect(),
+            pitch_scale: PitchScale::default(),
+            root_note: 60.0,
+            glide: Slew::default(),
+        })
+    }
+
+    fn on(voice: usize, note: f32) -> (usize, VoiceEvent) {
+        (
+            voice,
+            VoiceEvent::On {
+                note,
+                velocity: 1.0,
+            },
+        )
+    }
+
+    fn off(voice: usize) -> (usize, VoiceEvent) {
+        (voice, VoiceEvent::Off)
+    }
+
+    #[test]
+    fn round_robin_cycles_and_steals_the_oldest() {
+        let mut voices = allocator(Priority::RoundRobin, 2, false);
+        assert_eq!(voices.note(60.0, 1.0), [on(0, 60.0)]);
+        assert_eq!(voices.note(60.0, 0.0), [off(0)]);
+        // The next note takes the voice after the one used last.
+        assert_eq!(voices.note(62.0, 1.0), [on(1, 62.0)]);
+        assert_eq!(voices.note(64.0, 1.0), [on(0, 64.0)]);
+        // All busy: the oldest note, 62, loses its voice.
+        assert_eq!(voices.note(67.0, 1.0), [on(1, 67.0)]);
+    }
+
+    #[test]
+    fn last_takes_the_lowest_free_voice() {
+        let mut voices = allocator(Priority::Last, 2, false);
+        assert_eq!(voices.note(60.0, 1.0), [on(0, 60.0)]);
+        assert_eq!(voices.note(60.0, 0.0), [off(0)]);
+        assert_eq!(voices.note(62.0, 1.0), [on(0, 62.0)]);
+        assert_eq!(voices.note(64.0, 1.0), [on(1, 64.0)]);
+        assert_eq!(voices.note(67.0, 1.0), [on(0, 67.0)]);
+    }
+
+    #[test]
+    fn lowest_keeps_the_lowest_notes() {
+        let mut voices = allocator(Priority::Lowest, 2, false);
+        assert_eq!(voices.note(64.0, 1.0), [on(0, 64.0)]);
+        assert_eq!(voices.note(60.0, 1.0), [on(1, 60.0)]);
+        // 62 is lower than 64, so it steals that voice.
+        assert_eq!(voices.note(62.0, 1.0), [on(0, 62.0)]);
+        // 67 is higher than both and doesn't sound.
+        assert_eq!(voices.note(67.0, 1.0), []);
+        // Releasing 60 lets the highest of the rest, 64, sound again.
+        assert_eq!(voices.note(60.0, 0.0), [on(1, 64.0)]);
+    }
+
+    #[test]
+    fn highest_keeps_the_highest_notes() {
+        let mut voices = allocator(Priority::Highest, 2, false);
+        assert_eq!(voices.note(60.0, 1.0), [on(0, 60.0)]);
+        assert_eq!(voices.note(64.0, 1.0), [on(1, 64.0)]);
+        assert_eq!(voices.note(62.0, 1.0), [on(0, 62.0)]);
+        assert_eq!(voices.note(55.0, 1.0), []);
+        assert_eq!(voices.note(64.0, 0.0), [on(1, 60.0)]);
+    }
+
+    #[test]
+    fn releasing_a_stolen_note_changes_nothing() {
+        let mut voices = allocator(Priority::RoundRobin, 2, false);
+        voices.note(60.0, 1.0);
+        voices.note(62.0, 1.0);
+        assert_eq!(voices.note(64.0, 1.0), [on(0, 64.0)]);
+
+        assert_eq!(voices.note(60.0, 0.0), []);
+        // With 60 gone, releasing 64 frees its voice instead of bringing 60 back.
+        assert_eq!(voices.note(64.0, 0.0), [off(0)]);
+        assert_eq!(voices.note(62.0, 0.0), [off(1)]);
+    }
+
+    #[test]
+    fn a_stolen_note_still_held_comes_back() {
+        let mut voices = allocator(Priority::RoundRobin, 2, false);
+        voices.note(60.0, 1.0);
+        voices.note(62.0, 1.0);
+        voices.note(64.0, 1.0);
+        // The voice moves straight to 60 without closing its gate.
+        assert_eq!(voices.note(64.0, 0.0), [on(0, 60.0)]);
+    }
+
+    #[test]
+    fn restriking_a_held_note_retriggers_its_voice() {
+        let mut voices = allocator(Priority::RoundRobin, 2, false);
+        voices.note(60.0, 1.0);
+        voices.note(62.0, 1.0);
+        assert_eq!(
+            voices.note(60.0, 0.5),
+            [(
+                0,
+                VoiceEvent::On {
+                    note: 60.0,
+                    velocity: 0.5,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn unison_plays_one_note_on_every_voice() {
+        let mut voices = allocator(Priority::RoundRobin, 2, true);
+        assert_eq!(voices.note(60.0, 1.0), [on(0, 60.0), on(1, 60.0)]);
+        assert_eq!(voices.note(62.0, 1.0), [on(0, 62.0), on(1, 62.0)]);
+        assert_eq!(voices.note(62.0, 0.0), [on(0, 60.0), on(1, 60.0)]);
+        assert_eq!(voices.note(60.0, 0.0), [off(0), off(1)]);
+    }
+
+    #[test]
+    fn release_all_closes_open_gates() {
+        let mut voices = allocator(Priority::RoundRobin, 3, false);
+        voices.note(60.0, 1.0);
+        voices.note(62.0, 1.0);
+        assert_eq!(voices.release_all(), [off(0), off(1)]);
+        assert_eq!(voices.note(64.0, 1.0), [on(2, 64.0)]);
+    }
+}